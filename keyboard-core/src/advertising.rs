@@ -0,0 +1,303 @@
+use defmt::Format;
+
+/// Longest legacy advertising or scan response payload.
+pub const MAX_ADV_LEN: usize = 31;
+
+/// AD types, see the Bluetooth assigned numbers.
+mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const UUIDS_16_COMPLETE: u8 = 0x03;
+    pub const UUIDS_128_COMPLETE: u8 = 0x07;
+    pub const SHORT_NAME: u8 = 0x08;
+    pub const COMPLETE_NAME: u8 = 0x09;
+    pub const TX_POWER: u8 = 0x0A;
+    pub const APPEARANCE: u8 = 0x19;
+    pub const MANUFACTURER_DATA: u8 = 0xFF;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AdvError {
+    /// The field needs `needed` bytes but only `remaining` are left in the payload.
+    TooLong { needed: usize, remaining: usize },
+    /// Not even a single character of the name fits into the scan response.
+    NameDoesNotFit,
+}
+
+/// A single typed AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdField<'a> {
+    Flags(u8),
+    Uuids16(&'a [u16]),
+    /// 128-bit UUIDs in little endian byte order.
+    Uuids128(&'a [[u8; 16]]),
+    Appearance(u16),
+    TxPower(i8),
+    ManufacturerData {
+        company: u16,
+        data: &'a [u8],
+    },
+    CompleteName(&'a str),
+    ShortName(&'a str),
+}
+
+impl AdField<'_> {
+    fn ad_type(&self) -> u8 {
+        match self {
+            AdField::Flags(_) => ad_type::FLAGS,
+            AdField::Uuids16(_) => ad_type::UUIDS_16_COMPLETE,
+            AdField::Uuids128(_) => ad_type::UUIDS_128_COMPLETE,
+            AdField::Appearance(_) => ad_type::APPEARANCE,
+            AdField::TxPower(_) => ad_type::TX_POWER,
+            AdField::ManufacturerData { .. } => ad_type::MANUFACTURER_DATA,
+            AdField::CompleteName(_) => ad_type::COMPLETE_NAME,
+            AdField::ShortName(_) => ad_type::SHORT_NAME,
+        }
+    }
+
+    /// Length of the data, without the length and type bytes.
+    fn data_len(&self) -> usize {
+        match self {
+            AdField::Flags(_) | AdField::TxPower(_) => 1,
+            AdField::Appearance(_) => 2,
+            AdField::Uuids16(uuids) => uuids.len() * 2,
+            AdField::Uuids128(uuids) => uuids.len() * 16,
+            AdField::ManufacturerData { data, .. } => 2 + data.len(),
+            AdField::CompleteName(name) | AdField::ShortName(name) => name.len(),
+        }
+    }
+
+    fn write_data(&self, out: &mut [u8]) {
+        let mut pos = 0;
+        let mut put = |bytes: &[u8]| {
+            out[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+        };
+
+        match *self {
+            AdField::Flags(flags) => put(&[flags]),
+            AdField::TxPower(power) => put(&power.to_le_bytes()),
+            AdField::Appearance(appearance) => put(&appearance.to_le_bytes()),
+            AdField::Uuids16(uuids) => uuids.iter().for_each(|uuid| put(&uuid.to_le_bytes())),
+            AdField::Uuids128(uuids) => uuids.iter().for_each(|uuid| put(uuid)),
+            AdField::ManufacturerData { company, data } => {
+                put(&company.to_le_bytes());
+                put(data);
+            }
+            AdField::CompleteName(name) | AdField::ShortName(name) => put(name.as_bytes()),
+        }
+    }
+}
+
+/// An advertising or scan response payload, built from AD structures.
+///
+/// Every push is checked against `MAX_ADV_LEN`, a payload that was built without error is
+/// always valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdStructures {
+    buf: [u8; MAX_ADV_LEN],
+    len: usize,
+}
+
+impl Default for AdStructures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdStructures {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_ADV_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn remaining(&self) -> usize {
+        MAX_ADV_LEN - self.len
+    }
+
+    /// True if the field fits into the remaining space.
+    pub fn fits(&self, field: &AdField) -> bool {
+        2 + field.data_len() <= self.remaining()
+    }
+
+    pub fn push(&mut self, field: AdField) -> Result<&mut Self, AdvError> {
+        let data_len = field.data_len();
+        if !self.fits(&field) {
+            return Err(AdvError::TooLong {
+                needed: 2 + data_len,
+                remaining: self.remaining(),
+            });
+        }
+
+        // The length covers the type byte and the data.
+        self.buf[self.len] = data_len as u8 + 1;
+        self.buf[self.len + 1] = field.ad_type();
+        field.write_data(&mut self.buf[self.len + 2..self.len + 2 + data_len]);
+        self.len += 2 + data_len;
+        Ok(self)
+    }
+
+    /// Pushes the complete name if it fits, otherwise as much of it as fits as a short name.
+    pub fn push_name(&mut self, name: &str) -> Result<&mut Self, AdvError> {
+        if self.fits(&AdField::CompleteName(name)) {
+            return self.push(AdField::CompleteName(name));
+        }
+
+        let max = self.remaining().saturating_sub(2);
+        let end = (0..=max.min(name.len()))
+            .rev()
+            .find(|&i| name.is_char_boundary(i))
+            .unwrap_or(0);
+        if end == 0 {
+            return Err(AdvError::NameDoesNotFit);
+        }
+        self.push(AdField::ShortName(&name[..end]))
+    }
+}
+
+/// Advertising data and scan response, ready to be handed to the SoftDevice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvPayload {
+    pub adv_data: AdStructures,
+    pub scan_data: AdStructures,
+}
+
+impl AdvPayload {
+    /// Puts `fields` into the advertising data, and the name right after them if it still
+    /// fits. Otherwise the name goes into the scan response, shortened if needed.
+    pub fn new(fields: &[AdField], name: &str) -> Result<Self, AdvError> {
+        let mut adv_data = AdStructures::new();
+        let mut scan_data = AdStructures::new();
+
+        for field in fields {
+            adv_data.push(*field)?;
+        }
+
+        if adv_data.fits(&AdField::CompleteName(name)) {
+            adv_data.push(AdField::CompleteName(name))?;
+        } else {
+            scan_data.push_name(name)?;
+        }
+
+        Ok(Self {
+            adv_data,
+            scan_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// LE only, general discoverable.
+    const FLAGS: u8 = 0x06;
+    /// HID keyboard.
+    const APPEARANCE: u16 = 0x03C1;
+    /// HID, device information and battery service.
+    const UUIDS: [u16; 3] = [0x1812, 0x180A, 0x180F];
+
+    fn fields() -> [AdField<'static>; 3] {
+        [
+            AdField::Flags(FLAGS),
+            AdField::Appearance(APPEARANCE),
+            AdField::Uuids16(&UUIDS),
+        ]
+    }
+
+    const FIELD_BYTES: [u8; 15] = [
+        0x02, 0x01, 0x06, // Flags
+        0x03, 0x19, 0xC1, 0x03, // Appearance
+        0x07, 0x03, 0x12, 0x18, 0x0A, 0x18, 0x0F, 0x18, // 16-bit UUIDs
+    ];
+
+    #[test]
+    fn default_payload() {
+        let payload = AdvPayload::new(&fields(), "Rust Keyboard").unwrap();
+
+        let mut expected = Vec::from(FIELD_BYTES);
+        expected.extend_from_slice(&[0x0E, 0x09]);
+        expected.extend_from_slice(b"Rust Keyboard");
+        assert_eq!(payload.adv_data.as_bytes(), expected.as_slice());
+        assert_eq!(payload.scan_data.as_bytes(), &[]);
+    }
+
+    #[test]
+    fn name_spills_into_scan_response() {
+        // One character longer than the 14 that fit after the other fields.
+        let payload = AdvPayload::new(&fields(), "Rust Keyboard!!").unwrap();
+
+        assert_eq!(payload.adv_data.as_bytes(), &FIELD_BYTES);
+        let mut expected = Vec::from([0x10, 0x09]);
+        expected.extend_from_slice(b"Rust Keyboard!!");
+        assert_eq!(payload.scan_data.as_bytes(), expected.as_slice());
+    }
+
+    #[test]
+    fn long_name_is_shortened() {
+        let name = "abcdefghijklmnopqrstuvwxyz0123456789";
+        let payload = AdvPayload::new(&fields(), name).unwrap();
+
+        let mut expected = Vec::from([0x1E, 0x08]);
+        expected.extend_from_slice(&name.as_bytes()[..29]);
+        assert_eq!(payload.scan_data.as_bytes(), expected.as_slice());
+    }
+
+    #[test]
+    fn short_name_ends_on_char_boundary() {
+        // 'é' takes bytes 28 and 29, so only 28 of the 29 free bytes can be used.
+        let name = "abcdefghijklmnopqrstuvwxyz01é";
+        let payload = AdvPayload::new(&fields(), name).unwrap();
+
+        let mut expected = Vec::from([0x1D, 0x08]);
+        expected.extend_from_slice(&name.as_bytes()[..28]);
+        assert_eq!(payload.scan_data.as_bytes(), expected.as_slice());
+    }
+
+    #[test]
+    fn fields_that_do_not_fit() {
+        let uuids = [[0; 16], [1; 16]];
+        assert_eq!(
+            AdvPayload::new(&[AdField::Uuids128(&uuids)], "Rust Keyboard"),
+            Err(AdvError::TooLong {
+                needed: 34,
+                remaining: 31
+            })
+        );
+
+        let mut data = AdStructures::new();
+        data.push(AdField::Flags(FLAGS)).unwrap();
+        assert_eq!(
+            data.push(AdField::ManufacturerData {
+                company: 0xFFFF,
+                data: &[0; 26],
+            }),
+            Err(AdvError::TooLong {
+                needed: 30,
+                remaining: 28
+            })
+        );
+    }
+
+    #[test]
+    fn name_does_not_fit() {
+        let mut data = AdStructures::new();
+        data.push(AdField::ManufacturerData {
+            company: 0xFFFF,
+            data: &[0; 26],
+        })
+        .unwrap();
+
+        assert_eq!(data.remaining(), 1);
+        assert_eq!(
+            data.push_name("Rust Keyboard"),
+            Err(AdvError::NameDoesNotFit)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use defmt::Format;
+use embassy_time::Duration;
+
+/// Supply rail the battery is connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BatterySource {
+    /// Battery on VDDH, sampled through the internal divide-by-5.
+    Vddh,
+    /// Battery directly on VDD, e.g. a coin cell.
+    Vdd,
+}
+
+impl BatterySource {
+    fn divider(self) -> u32 {
+        match self {
+            BatterySource::Vddh => 5,
+            BatterySource::Vdd => 1,
+        }
+    }
+}
+
+/// Battery voltage in millivolts to charge in percent, sorted by falling voltage.
+/// Voltages between two points are interpolated linearly.
+#[derive(Debug, Clone, Copy)]
+pub struct DischargeCurve(pub &'static [(u16, u8)]);
+
+impl DischargeCurve {
+    pub const LIPO: DischargeCurve = DischargeCurve(&[
+        (4200, 100),
+        (4150, 95),
+        (4110, 90),
+        (4080, 85),
+        (4020, 80),
+        (3980, 75),
+        (3950, 70),
+        (3910, 65),
+        (3870, 60),
+        (3850, 55),
+        (3840, 50),
+        (3820, 45),
+        (3800, 40),
+        (3790, 35),
+        (3770, 30),
+        (3750, 25),
+        (3730, 20),
+        (3710, 15),
+        (3690, 10),
+        (3610, 5),
+        (3270, 0),
+    ]);
+
+    /// CR2032 under the light load of a keyboard.
+    pub const COIN_CELL: DischargeCurve = DischargeCurve(&[
+        (3000, 100),
+        (2900, 80),
+        (2800, 60),
+        (2700, 40),
+        (2600, 30),
+        (2500, 20),
+        (2400, 10),
+        (2000, 0),
+    ]);
+
+    pub fn percent(&self, millivolts: u16) -> u8 {
+        let points = self.0;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return 0;
+        };
+
+        if millivolts >= first.0 {
+            return first.1;
+        }
+
+        points
+            .windows(2)
+            .find(|pair| millivolts >= pair[1].0)
+            .map(|pair| {
+                let ((high_mv, high), (low_mv, low)) = (pair[0], pair[1]);
+                let span = (high_mv - low_mv) as u32;
+                let offset = (millivolts - low_mv) as u32;
+                low + ((high - low) as u32 * offset / span) as u8
+            })
+            .unwrap_or(last.1)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryConfig {
+    pub source: BatterySource,
+    pub curve: DischargeCurve,
+    /// Minimum change in percent before the level is updated and notified.
+    pub threshold: u8,
+    /// Time between two measurements.
+    pub interval: Duration,
+}
+
+impl BatteryConfig {
+    /// Converts a 12-bit sample taken with gain 1/6 and the internal 0.6 V reference.
+    pub fn millivolts(&self, sample: i16) -> u16 {
+        const FULL_SCALE_MV: u32 = 3600;
+        let mv = sample.max(0) as u32 * FULL_SCALE_MV * self.source.divider() / 4096;
+        mv.min(u16::MAX as u32) as u16
+    }
+
+    /// Returns the level to report if it moved far enough from the last reported one.
+    pub fn report(&self, reported: Option<u8>, level: u8) -> Option<u8> {
+        match reported {
+            Some(reported) if reported.abs_diff(level) < self.threshold => None,
+            _ => Some(level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn config(threshold: u8) -> BatteryConfig {
+        BatteryConfig {
+            source: BatterySource::Vddh,
+            curve: DischargeCurve::LIPO,
+            threshold,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn lipo_end_points() {
+        assert_eq!(DischargeCurve::LIPO.percent(4200), 100);
+        assert_eq!(DischargeCurve::LIPO.percent(3270), 0);
+    }
+
+    #[test]
+    fn lipo_between_points() {
+        assert_eq!(DischargeCurve::LIPO.percent(3850), 55);
+        assert_eq!(DischargeCurve::LIPO.percent(4175), 97);
+        assert_eq!(DischargeCurve::LIPO.percent(3440), 2);
+    }
+
+    #[test]
+    fn coin_cell_end_points() {
+        assert_eq!(DischargeCurve::COIN_CELL.percent(3000), 100);
+        assert_eq!(DischargeCurve::COIN_CELL.percent(2000), 0);
+    }
+
+    #[test]
+    fn coin_cell_between_points() {
+        assert_eq!(DischargeCurve::COIN_CELL.percent(2700), 40);
+        assert_eq!(DischargeCurve::COIN_CELL.percent(2950), 90);
+        assert_eq!(DischargeCurve::COIN_CELL.percent(2200), 5);
+    }
+
+    #[test]
+    fn outside_the_curve_is_clamped() {
+        assert_eq!(DischargeCurve::LIPO.percent(4500), 100);
+        assert_eq!(DischargeCurve::LIPO.percent(3000), 0);
+        assert_eq!(DischargeCurve::COIN_CELL.percent(3300), 100);
+        assert_eq!(DischargeCurve::COIN_CELL.percent(0), 0);
+        assert_eq!(DischargeCurve(&[]).percent(3700), 0);
+    }
+
+    #[test]
+    fn curves_fall_with_the_voltage() {
+        for curve in [DischargeCurve::LIPO, DischargeCurve::COIN_CELL] {
+            let levels: Vec<u8> = (0..=4500).rev().map(|mv| curve.percent(mv)).collect();
+            assert!(levels.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+    }
+
+    #[test]
+    fn first_level_is_always_reported() {
+        assert_eq!(config(2).report(None, 50), Some(50));
+    }
+
+    #[test]
+    fn report_needs_threshold_change() {
+        let battery = config(2);
+        assert_eq!(battery.report(Some(50), 50), None);
+        assert_eq!(battery.report(Some(50), 51), None);
+        assert_eq!(battery.report(Some(50), 49), None);
+        assert_eq!(battery.report(Some(50), 52), Some(52));
+        assert_eq!(battery.report(Some(50), 48), Some(48));
+    }
+
+    #[test]
+    fn no_threshold_reports_every_level() {
+        assert_eq!(config(0).report(Some(50), 50), Some(50));
+    }
+
+    #[test]
+    fn millivolts_from_sample() {
+        let battery = config(2);
+        assert_eq!(battery.millivolts(1000), 4394);
+        assert_eq!(battery.millivolts(-5), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::hid::descriptor::MAX_REPORTS;
+use core::ops::{Deref, DerefMut};
+use defmt::{error, Format};
+use serde::{Deserialize, Serialize};
+use tinyvec::ArrayVec;
+
+/// Number of hosts that can be bonded at the same time.
+pub const HOST_SLOTS: usize = 3;
+
+/// Room for the CCCDs of all notifying characteristics plus the SoftDevice's checksum, rounded
+/// up to a size tinyvec supports.
+pub const SYS_ATTRS_LEN: usize = 128;
+
+/// Every CCCD takes a handle, length and value of two bytes each. Besides the reports the boot
+/// keyboard input, battery level and service changed characteristics can notify.
+const _: () = assert!(SYS_ATTRS_LEN >= (MAX_REPORTS + 3) * 6 + 4);
+
+/// The GATT system attributes (CCCD state) of a bonded peer, as the SoftDevice hands them out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SysAttrs(ArrayVec<[u8; SYS_ATTRS_LEN]>);
+
+impl Deref for SysAttrs {
+    type Target = ArrayVec<[u8; SYS_ATTRS_LEN]>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SysAttrs {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Format for SysAttrs {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "SysAttrs  {=[u8]:#X}", &self)
+    }
+}
+
+impl SysAttrs {
+    /// Reads the system attributes with `get`, which fills the buffer and returns their length.
+    /// Returns true if they changed and need to be persisted.
+    pub fn update<E: Format>(&mut self, get: impl FnOnce(&mut [u8]) -> Result<usize, E>) -> bool {
+        let mut buf = [0u8; SYS_ATTRS_LEN];
+        let len = match get(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Failed to get system attributes: {}", e);
+                return false;
+            }
+        };
+
+        let Some(attrs) = buf.get(..len) else {
+            error!("System attributes don't fit: {} bytes", len);
+            return false;
+        };
+        if self.as_slice() == attrs {
+            return false;
+        }
+
+        self.clear();
+        self.extend_from_slice(attrs);
+        true
+    }
+}
+
+/// Restores stored system attributes with `set`, or the defaults if none were stored.
+pub fn restore_sys_attrs<E: Format>(
+    attrs: Option<&SysAttrs>,
+    set: impl Fn(Option<&[u8]>) -> Result<(), E>,
+) {
+    let attrs = attrs.filter(|attrs| !attrs.is_empty());
+
+    if let Err(e) = set(attrs.map(|attrs| attrs.as_slice())) {
+        error!("Failed to restore system attributes: {}", e);
+        // Stored attributes may be stale after the GATT table changed, fall back to defaults.
+        if attrs.is_some() {
+            if let Err(e) = set(None) {
+                error!("Failed to set default system attributes: {}", e);
+            }
+        }
+    }
+}
+
+/// Flat representation of a bonded peer as stored in flash, since the SoftDevice types don't
+/// implement serde.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub ediv: u16,
+    pub rand: [u8; 8],
+    pub ltk: [u8; 16],
+    pub ltk_flags: u8,
+    pub irk: [u8; 16],
+    pub addr_flags: u8,
+    pub addr: [u8; 6],
+    pub sys_attrs: SysAttrs,
+}
+
+/// Versioned envelope around the stored host slots, so the layout can be migrated later on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownPeersRecord {
+    version: u8,
+    peers: [Option<PeerRecord>; HOST_SLOTS],
+}
+
+impl KnownPeersRecord {
+    pub const VERSION: u8 = 1;
+
+    pub fn new(peers: [Option<PeerRecord>; HOST_SLOTS]) -> Self {
+        Self {
+            version: Self::VERSION,
+            peers,
+        }
+    }
+
+    /// The stored host slots, if they were written in the current layout.
+    pub fn peers(self) -> Result<[Option<PeerRecord>; HOST_SLOTS], UnsupportedVersion> {
+        match self.version {
+            Self::VERSION => Ok(self.peers),
+            version => Err(UnsupportedVersion(version)),
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub struct UnsupportedVersion(pub u8);
+
+impl core::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unsupported known peers version {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    /// The SoftDevice rejected the request.
+    #[derive(Debug, Format)]
+    struct Rejected;
+
+    fn peer(id: u8) -> PeerRecord {
+        PeerRecord {
+            ediv: id as u16,
+            rand: [id; 8],
+            ltk: [id; 16],
+            ltk_flags: 1,
+            irk: [id; 16],
+            addr_flags: 0,
+            addr: [id; 6],
+            sys_attrs: SysAttrs::default(),
+        }
+    }
+
+    fn sys_attrs(attrs: &[u8]) -> SysAttrs {
+        let mut sys_attrs = SysAttrs::default();
+        assert!(sys_attrs.update(|buf: &mut [u8]| {
+            buf[..attrs.len()].copy_from_slice(attrs);
+            Ok::<_, Rejected>(attrs.len())
+        }));
+        sys_attrs
+    }
+
+    /// Restores `attrs` like the SoftDevice would, returning what ended up restored. None
+    /// stands for the defaults.
+    fn restore(attrs: Option<&SysAttrs>, reject_stored: bool) -> Option<Vec<u8>> {
+        let restored = RefCell::new(Some(Vec::from([0xEE])));
+        restore_sys_attrs(attrs, |attrs| {
+            if reject_stored && attrs.is_some() {
+                return Err(Rejected);
+            }
+            *restored.borrow_mut() = attrs.map(|attrs| attrs.to_vec());
+            Ok(())
+        });
+        restored.into_inner()
+    }
+
+    #[test]
+    fn known_peers_round_trip() {
+        let mut second = peer(2);
+        second.sys_attrs = sys_attrs(&[1, 2, 3, 4]);
+        let record = KnownPeersRecord::new([Some(peer(1)), Some(second), None]);
+
+        let mut buf = [0u8; 1024];
+        let bytes = postcard::to_slice(&record, &mut buf).unwrap();
+        assert_eq!(bytes[0], KnownPeersRecord::VERSION);
+
+        let restored: KnownPeersRecord = postcard::from_bytes(bytes).unwrap();
+        assert_eq!(
+            restored.peers().unwrap(),
+            [Some(peer(1)), Some(second), None]
+        );
+    }
+
+    #[test]
+    fn known_peers_reject_other_versions() {
+        let record = KnownPeersRecord::new([Some(peer(1)), None, None]);
+        let mut buf = [0u8; 1024];
+        let bytes = postcard::to_slice(&record, &mut buf).unwrap();
+
+        bytes[0] = KnownPeersRecord::VERSION + 1;
+        let restored: KnownPeersRecord = postcard::from_bytes(bytes).unwrap();
+        assert!(matches!(
+            restored.peers(),
+            Err(UnsupportedVersion(version)) if version == KnownPeersRecord::VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn sys_attrs_fit_every_cccd() {
+        // All reports, boot keyboard input, battery level and service changed, plus the CRC.
+        let attrs: Vec<u8> = (0..(MAX_REPORTS + 3) * 6 + 4).map(|i| i as u8).collect();
+        assert_eq!(sys_attrs(&attrs).as_slice(), attrs.as_slice());
+    }
+
+    #[test]
+    fn update_only_reports_changes() {
+        let mut attrs = sys_attrs(&[1, 2, 3, 4]);
+        let get = |values: [u8; 4]| {
+            move |buf: &mut [u8]| {
+                buf[..4].copy_from_slice(&values);
+                Ok::<_, Rejected>(4)
+            }
+        };
+
+        assert!(!attrs.update(get([1, 2, 3, 4])));
+        assert!(attrs.update(get([5, 2, 3, 4])));
+        assert_eq!(attrs.as_slice(), &[5, 2, 3, 4]);
+    }
+
+    #[test]
+    fn update_ignores_failures() {
+        let mut attrs = sys_attrs(&[1, 2]);
+
+        assert!(!attrs.update(|_: &mut [u8]| Err(Rejected)));
+        assert!(!attrs.update(|_: &mut [u8]| Ok::<_, Rejected>(SYS_ATTRS_LEN + 1)));
+        assert_eq!(attrs.as_slice(), &[1, 2]);
+    }
+
+    #[test]
+    fn restore_stored() {
+        let attrs = sys_attrs(&[1, 2, 3, 4]);
+        assert_eq!(restore(Some(&attrs), false), Some(Vec::from([1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn restore_falls_back_to_defaults() {
+        // Not bonded.
+        assert_eq!(restore(None, false), None);
+        // Nothing stored for the peer.
+        assert_eq!(restore(Some(&SysAttrs::default()), false), None);
+        // Stale after the GATT table changed.
+        let attrs = sys_attrs(&[1, 2, 3, 4]);
+        assert_eq!(restore(Some(&attrs), true), None);
+    }
+}
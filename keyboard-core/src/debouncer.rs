@@ -0,0 +1,135 @@
+pub struct Debouncer {
+    counter: i8,
+    state: DebounceState,
+    thres_steady: i8,
+    thres_transient_abs: i8,
+}
+
+enum DebounceState {
+    SteadyStateLow,
+    TransientLowHigh,
+    SteadyStateHigh,
+    TransientHighLow,
+}
+
+impl Debouncer {
+    pub fn new(initial_value: bool, thres_transient: i8, thres_steady: i8) -> Self {
+        assert!(thres_steady > thres_transient);
+
+        let state = if initial_value {
+            DebounceState::SteadyStateHigh
+        } else {
+            DebounceState::SteadyStateLow
+        };
+
+        let counter = if initial_value {
+            thres_steady
+        } else {
+            -thres_steady
+        };
+
+        Debouncer {
+            counter,
+            state,
+            thres_steady,
+            thres_transient_abs: thres_steady - thres_transient,
+        }
+    }
+
+    pub fn output(&self) -> bool {
+        matches!(
+            self.state,
+            DebounceState::TransientLowHigh | DebounceState::SteadyStateHigh
+        )
+    }
+
+    /// Feeds one raw sample into the debouncer and returns true if `output` changed.
+    pub fn update(&mut self, input: bool) -> bool {
+        let previous = self.output();
+
+        if input {
+            if self.counter < self.thres_steady {
+                self.counter += 1;
+            }
+        } else if self.counter > -self.thres_steady {
+            self.counter -= 1;
+        }
+
+        match self.state {
+            DebounceState::SteadyStateLow => {
+                if self.counter >= -self.thres_transient_abs {
+                    self.enter_transient(DebounceState::TransientLowHigh);
+                }
+            }
+            DebounceState::SteadyStateHigh => {
+                if self.counter <= self.thres_transient_abs {
+                    self.enter_transient(DebounceState::TransientHighLow);
+                }
+            }
+            DebounceState::TransientLowHigh | DebounceState::TransientHighLow => {
+                self.check_transition()
+            }
+        }
+
+        self.output() != previous
+    }
+
+    fn enter_transient(&mut self, next_state: DebounceState) {
+        self.counter = 0;
+        self.state = next_state;
+    }
+
+    fn check_transition(&mut self) {
+        match self.counter {
+            c if c == self.thres_steady => self.state = DebounceState::SteadyStateHigh,
+            c if c == -self.thres_steady => self.state = DebounceState::SteadyStateLow,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `inputs` and returns the indices of the samples that changed the output.
+    fn changes(debouncer: &mut Debouncer, inputs: &[bool]) -> [Option<usize>; 2] {
+        let mut changes = [None; 2];
+        let mut found = changes.iter_mut();
+        for (n, input) in inputs.iter().enumerate() {
+            if debouncer.update(*input) {
+                *found.next().expect("more than two changes") = Some(n);
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn starts_with_initial_value() {
+        assert!(Debouncer::new(true, 2, 5).output());
+        assert!(!Debouncer::new(false, 2, 5).output());
+    }
+
+    #[test]
+    fn changes_after_transient_threshold() {
+        let mut debouncer = Debouncer::new(false, 2, 5);
+        assert_eq!(changes(&mut debouncer, &[true; 10]), [Some(1), None]);
+        assert!(debouncer.output());
+        assert_eq!(changes(&mut debouncer, &[false; 10]), [Some(1), None]);
+        assert!(!debouncer.output());
+    }
+
+    #[test]
+    fn bouncing_input_changes_once() {
+        let mut debouncer = Debouncer::new(false, 2, 5);
+        let inputs = [true, false, true, true, false, true, true, true, true, true];
+        assert_eq!(changes(&mut debouncer, &inputs), [Some(3), None]);
+    }
+
+    #[test]
+    fn transient_state_needs_steady_threshold_to_change_back() {
+        let mut debouncer = Debouncer::new(false, 2, 5);
+        assert_eq!(changes(&mut debouncer, &[true, true]), [Some(1), None]);
+        assert_eq!(changes(&mut debouncer, &[false; 10]), [Some(4), None]);
+    }
+}
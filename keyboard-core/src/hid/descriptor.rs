@@ -0,0 +1,296 @@
+use super::{
+    CONSUMER_REPORT_LEN, LEDS_REPORT_LEN, MOUSE_REPORT_LEN, NKRO_MAX_USAGE, NKRO_REPORT_LEN,
+    SYSTEM_REPORT_LEN,
+};
+use defmt::Format;
+
+/// Longest report descriptor `ReportMap` can hold.
+pub const MAX_REPORT_MAP_LEN: usize = 512;
+/// Most input and output reports a `ReportMap` can describe.
+pub const MAX_REPORTS: usize = 8;
+
+/// Top-level collections a report map can be composed of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Collection {
+    /// 6KRO keyboard with the same layout as the boot keyboard report, with an LED output report.
+    Keyboard,
+    /// Modifier byte followed by a bitmap of keyboard usages up to `NKRO_MAX_USAGE`, with an LED
+    /// output report.
+    Nkro,
+    /// A single consumer control usage.
+    Consumer,
+    /// A single system control usage.
+    System,
+    /// Buttons, X/Y movement, vertical and horizontal wheel.
+    Mouse,
+}
+
+/// Report type as used by the Report Reference descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ReportKind {
+    Input = 0x01,
+    Output = 0x02,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ReportInfo {
+    pub id: u8,
+    pub collection: Collection,
+    pub kind: ReportKind,
+    /// Length without the report ID.
+    pub len: usize,
+}
+
+impl Collection {
+    /// Usage page, usage and start of the application collection, followed by the report ID.
+    const fn header(self) -> &'static [u8] {
+        match self {
+            Collection::Keyboard | Collection::Nkro => &[0x05, 0x01, 0x09, 0x06, 0xA1, 0x01],
+            Collection::Consumer => &[0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01],
+            Collection::System => &[0x05, 0x01, 0x09, 0x80, 0xA1, 0x01],
+            Collection::Mouse => &[0x05, 0x01, 0x09, 0x02, 0xA1, 0x01],
+        }
+    }
+
+    /// Report items up to and including the end of the collection.
+    const fn body(self) -> &'static [u8] {
+        match self {
+            Collection::Keyboard => KEYBOARD_BODY,
+            Collection::Nkro => NKRO_BODY,
+            Collection::Consumer => CONSUMER_BODY,
+            Collection::System => SYSTEM_BODY,
+            Collection::Mouse => MOUSE_BODY,
+        }
+    }
+
+    /// The reports of the collection, they all share its report ID.
+    const fn reports(self) -> &'static [(ReportKind, usize)] {
+        match self {
+            Collection::Keyboard => &[
+                (ReportKind::Input, 8),
+                (ReportKind::Output, LEDS_REPORT_LEN),
+            ],
+            Collection::Nkro => &[
+                (ReportKind::Input, NKRO_REPORT_LEN),
+                (ReportKind::Output, LEDS_REPORT_LEN),
+            ],
+            Collection::Consumer => &[(ReportKind::Input, CONSUMER_REPORT_LEN)],
+            Collection::System => &[(ReportKind::Input, SYSTEM_REPORT_LEN)],
+            Collection::Mouse => &[(ReportKind::Input, MOUSE_REPORT_LEN)],
+        }
+    }
+}
+
+#[rustfmt::skip]
+const KEYBOARD_BODY: &[u8] = &[
+    0x05, 0x07,                 //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,                 //   Usage Minimum (Left Control)
+    0x29, 0xE7,                 //   Usage Maximum (Right GUI)
+    0x15, 0x00,                 //   Logical Minimum (0)
+    0x25, 0x01,                 //   Logical Maximum (1)
+    0x75, 0x01,                 //   Report Size (1)
+    0x95, 0x08,                 //   Report Count (8)
+    0x81, 0x02,                 //   Input (Data, Variable, Absolute)
+    0x75, 0x08,                 //   Report Size (8)
+    0x95, 0x01,                 //   Report Count (1)
+    0x81, 0x01,                 //   Input (Constant)
+    0x19, 0x00,                 //   Usage Minimum (0)
+    0x2A, 0xFF, 0x00,           //   Usage Maximum (255)
+    0x15, 0x00,                 //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,           //   Logical Maximum (255)
+    0x75, 0x08,                 //   Report Size (8)
+    0x95, 0x06,                 //   Report Count (6)
+    0x81, 0x00,                 //   Input (Data, Array, Absolute)
+    0x25, 0x01,                 //   Logical Maximum (1)
+    0x05, 0x08,                 //   Usage Page (LEDs)
+    0x19, 0x01,                 //   Usage Minimum (Num Lock)
+    0x29, 0x05,                 //   Usage Maximum (Kana)
+    0x75, 0x01,                 //   Report Size (1)
+    0x95, 0x05,                 //   Report Count (5)
+    0x91, 0x02,                 //   Output (Data, Variable, Absolute)
+    0x75, 0x03,                 //   Report Size (3)
+    0x95, 0x01,                 //   Report Count (1)
+    0x91, 0x01,                 //   Output (Constant)
+    0xC0,                       // End Collection
+];
+
+#[rustfmt::skip]
+const NKRO_BODY: &[u8] = &[
+    0x05, 0x07,                 //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,                 //   Usage Minimum (Left Control)
+    0x29, 0xE7,                 //   Usage Maximum (Right GUI)
+    0x15, 0x00,                 //   Logical Minimum (0)
+    0x25, 0x01,                 //   Logical Maximum (1)
+    0x75, 0x01,                 //   Report Size (1)
+    0x95, 0x08,                 //   Report Count (8)
+    0x81, 0x02,                 //   Input (Data, Variable, Absolute)
+    0x19, 0x00,                 //   Usage Minimum (0)
+    0x29, NKRO_MAX_USAGE,       //   Usage Maximum
+    0x95, NKRO_MAX_USAGE + 1,   //   Report Count
+    0x81, 0x02,                 //   Input (Data, Variable, Absolute)
+    0x05, 0x08,                 //   Usage Page (LEDs)
+    0x19, 0x01,                 //   Usage Minimum (Num Lock)
+    0x29, 0x05,                 //   Usage Maximum (Kana)
+    0x95, 0x05,                 //   Report Count (5)
+    0x91, 0x02,                 //   Output (Data, Variable, Absolute)
+    0x75, 0x03,                 //   Report Size (3)
+    0x95, 0x01,                 //   Report Count (1)
+    0x91, 0x01,                 //   Output (Constant)
+    0xC0,                       // End Collection
+];
+
+#[rustfmt::skip]
+const CONSUMER_BODY: &[u8] = &[
+    0x19, 0x00,                 //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03,           //   Usage Maximum (0x3FF)
+    0x15, 0x00,                 //   Logical Minimum (0)
+    0x26, 0xFF, 0x03,           //   Logical Maximum (0x3FF)
+    0x75, 0x10,                 //   Report Size (16)
+    0x95, 0x01,                 //   Report Count (1)
+    0x81, 0x00,                 //   Input (Data, Array, Absolute)
+    0xC0,                       // End Collection
+];
+
+#[rustfmt::skip]
+const SYSTEM_BODY: &[u8] = &[
+    0x19, 0x81,                 //   Usage Minimum (System Power Down)
+    0x29, 0xB7,                 //   Usage Maximum (System Display LCD Autoscale)
+    0x16, 0x81, 0x00,           //   Logical Minimum (0x81)
+    0x26, 0xB7, 0x00,           //   Logical Maximum (0xB7)
+    0x75, 0x08,                 //   Report Size (8)
+    0x95, 0x01,                 //   Report Count (1)
+    0x81, 0x00,                 //   Input (Data, Array, Absolute)
+    0xC0,                       // End Collection
+];
+
+#[rustfmt::skip]
+const MOUSE_BODY: &[u8] = &[
+    0x09, 0x01,                 //   Usage (Pointer)
+    0xA1, 0x00,                 //   Collection (Physical)
+    0x05, 0x09,                 //     Usage Page (Button)
+    0x19, 0x01,                 //     Usage Minimum (1)
+    0x29, 0x05,                 //     Usage Maximum (5)
+    0x15, 0x00,                 //     Logical Minimum (0)
+    0x25, 0x01,                 //     Logical Maximum (1)
+    0x75, 0x01,                 //     Report Size (1)
+    0x95, 0x05,                 //     Report Count (5)
+    0x81, 0x02,                 //     Input (Data, Variable, Absolute)
+    0x75, 0x03,                 //     Report Size (3)
+    0x95, 0x01,                 //     Report Count (1)
+    0x81, 0x01,                 //     Input (Constant)
+    0x05, 0x01,                 //     Usage Page (Generic Desktop)
+    0x09, 0x30,                 //     Usage (X)
+    0x09, 0x31,                 //     Usage (Y)
+    0x09, 0x38,                 //     Usage (Wheel)
+    0x15, 0x81,                 //     Logical Minimum (-127)
+    0x25, 0x7F,                 //     Logical Maximum (127)
+    0x75, 0x08,                 //     Report Size (8)
+    0x95, 0x03,                 //     Report Count (3)
+    0x81, 0x06,                 //     Input (Data, Variable, Relative)
+    0x05, 0x0C,                 //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,           //     Usage (AC Pan)
+    0x95, 0x01,                 //     Report Count (1)
+    0x81, 0x06,                 //     Input (Data, Variable, Relative)
+    0xC0,                       //   End Collection
+    0xC0,                       // End Collection
+];
+
+/// Longest input or output report of the collections, without the report ID.
+pub const fn max_report_len(collections: &[Collection]) -> usize {
+    let mut max = 0;
+
+    let mut i = 0;
+    while i < collections.len() {
+        let reports = collections[i].reports();
+        let mut r = 0;
+        while r < reports.len() {
+            if reports[r].1 > max {
+                max = reports[r].1;
+            }
+            r += 1;
+        }
+        i += 1;
+    }
+    max
+}
+
+/// A HID report descriptor composed from a list of collections at compile time, together with
+/// the table of reports it describes.
+///
+/// Report IDs are assigned in the order the collections are given, starting at 1.
+pub struct ReportMap {
+    bytes: [u8; MAX_REPORT_MAP_LEN],
+    len: usize,
+    reports: [Option<ReportInfo>; MAX_REPORTS],
+}
+
+impl ReportMap {
+    pub const fn new(collections: &[Collection]) -> Self {
+        let mut map = ReportMap {
+            bytes: [0; MAX_REPORT_MAP_LEN],
+            len: 0,
+            reports: [None; MAX_REPORTS],
+        };
+        let mut count = 0;
+
+        let mut i = 0;
+        while i < collections.len() {
+            let collection = collections[i];
+            let id = i as u8 + 1;
+
+            map = map
+                .push(collection.header())
+                .push(&[0x85, id])
+                .push(collection.body());
+
+            let reports = collection.reports();
+            let mut r = 0;
+            while r < reports.len() {
+                assert!(count < MAX_REPORTS, "too many reports");
+                let (kind, len) = reports[r];
+                map.reports[count] = Some(ReportInfo {
+                    id,
+                    collection,
+                    kind,
+                    len,
+                });
+                count += 1;
+                r += 1;
+            }
+            i += 1;
+        }
+
+        map
+    }
+
+    const fn push(mut self, bytes: &[u8]) -> Self {
+        assert!(
+            self.len + bytes.len() <= MAX_REPORT_MAP_LEN,
+            "report map too long"
+        );
+
+        let mut i = 0;
+        while i < bytes.len() {
+            self.bytes[self.len] = bytes[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// The report descriptor.
+    pub const fn bytes(&self) -> &[u8] {
+        self.bytes.split_at(self.len).0
+    }
+
+    pub fn reports(&self) -> impl Iterator<Item = ReportInfo> + '_ {
+        self.reports.iter().flatten().copied()
+    }
+
+    /// The report ID of a collection, None if the map doesn't contain it.
+    pub fn id(&self, collection: Collection) -> Option<u8> {
+        self.reports()
+            .find(|report| report.collection == collection)
+            .map(|report| report.id)
+    }
+}
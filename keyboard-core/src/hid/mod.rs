@@ -0,0 +1,240 @@
+pub mod descriptor;
+
+use self::descriptor::{Collection, ReportMap};
+use crate::keymap::mouse::MouseReport;
+use crate::keymap::{KeySet, Report};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use packed_struct::PackedStruct;
+use usbd_human_interface_device::page::{Consumer, Desktop, Keyboard};
+
+/// Highest keyboard usage covered by the NKRO bitmap, everything above except modifiers is dropped.
+pub const NKRO_MAX_USAGE: u8 = 0x67;
+
+/// Report map of the keyboard, shared by BLE and USB.
+///
+/// Keys are sent as NKRO report if the map contains one, otherwise as 6KRO report, so only one
+/// of them is listed. The 6KRO report has the same layout as the boot keyboard report, so
+/// `BootKeyboardReport` can be packed into either.
+pub static REPORT_MAP: ReportMap = ReportMap::new(COLLECTIONS);
+
+const COLLECTIONS: &[Collection] = &[
+    Collection::Nkro,
+    Collection::Consumer,
+    Collection::System,
+    Collection::Mouse,
+];
+
+/// Longest report in any of the collections, without the report ID.
+pub const MAX_REPORT_LEN: usize = descriptor::max_report_len(COLLECTIONS);
+
+pub const NKRO_REPORT_LEN: usize = 1 + (NKRO_MAX_USAGE as usize + 1) / 8;
+
+/// Modifier byte followed by one bit per keyboard usage up to `NKRO_MAX_USAGE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct NkroReport(pub [u8; NKRO_REPORT_LEN]);
+
+impl From<&KeySet> for NkroReport {
+    fn from(keys: &KeySet) -> Self {
+        let mut report = [0u8; NKRO_REPORT_LEN];
+
+        for key in keys.iter() {
+            let usage = key as u8;
+            if (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&usage) {
+                report[0] |= 1 << (usage - Keyboard::LeftControl as u8);
+            } else if usage <= NKRO_MAX_USAGE {
+                report[1 + usage as usize / 8] |= 1 << (usage % 8);
+            }
+        }
+
+        NkroReport(report)
+    }
+}
+
+impl AsRef<[u8]> for NkroReport {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+pub const CONSUMER_REPORT_LEN: usize = 2;
+pub const SYSTEM_REPORT_LEN: usize = 1;
+pub const LEDS_REPORT_LEN: usize = 1;
+
+/// The held consumer control usage as a little endian 16-bit usage, 0 if none is held.
+pub fn consumer_report(usage: Option<Consumer>) -> [u8; CONSUMER_REPORT_LEN] {
+    usage.map_or(0, |usage| usage as u16).to_le_bytes()
+}
+
+/// The held system control usage, 0 is outside the logical range and means none is held.
+pub fn system_report(usage: Option<Desktop>) -> [u8; SYSTEM_REPORT_LEN] {
+    [usage.map_or(0, |usage| usage as u8)]
+}
+
+pub const MOUSE_REPORT_LEN: usize = 5;
+
+/// Buttons followed by X, Y, wheel and horizontal pan.
+pub fn mouse_report(report: &MouseReport) -> [u8; MOUSE_REPORT_LEN] {
+    [
+        report.buttons,
+        report.x as u8,
+        report.y as u8,
+        report.wheel as u8,
+        report.pan as u8,
+    ]
+}
+
+impl ReportMap {
+    /// Encodes a report from the keymap into `buf`, returning the report ID and length.
+    /// None if the map has no collection for it.
+    pub fn encode(&self, report: &Report, buf: &mut [u8; MAX_REPORT_LEN]) -> Option<(u8, usize)> {
+        let mut put = |id: u8, bytes: &[u8]| {
+            buf[..bytes.len()].copy_from_slice(bytes);
+            Some((id, bytes.len()))
+        };
+
+        match report {
+            Report::Keyboard(keys) => match self.id(Collection::Nkro) {
+                Some(id) => put(id, NkroReport::from(keys).as_ref()),
+                None => put(
+                    self.id(Collection::Keyboard)?,
+                    &keys.boot_report().pack().unwrap_or_default(),
+                ),
+            },
+            Report::Consumer(usage) => {
+                put(self.id(Collection::Consumer)?, &consumer_report(*usage))
+            }
+            Report::System(usage) => put(self.id(Collection::System)?, &system_report(*usage)),
+            Report::Mouse(report) => put(self.id(Collection::Mouse)?, &mouse_report(report)),
+        }
+    }
+}
+
+/// The HID protocol selected by the host through the Protocol Mode characteristic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ProtocolMode {
+    Boot = 0x00,
+    Report = 0x01,
+}
+
+impl ProtocolMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(ProtocolMode::Boot),
+            0x01 => Some(ProtocolMode::Report),
+            _ => None,
+        }
+    }
+}
+
+/// Commands written by the host to the HID Control Point characteristic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ControlPoint {
+    Suspend = 0x00,
+    ExitSuspend = 0x01,
+}
+
+impl ControlPoint {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(ControlPoint::Suspend),
+            0x01 => Some(ControlPoint::ExitSuspend),
+            _ => None,
+        }
+    }
+}
+
+/// Keyboard LEDs in the order of the LED output report bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Led {
+    NumLock = 0,
+    CapsLock = 1,
+    ScrollLock = 2,
+    Compose = 3,
+    Kana = 4,
+}
+
+/// LED state written by the host through the LED output report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Leds(pub u8);
+
+impl Leds {
+    pub fn is_on(&self, led: Led) -> bool {
+        self.0 & (1 << led as u8) != 0
+    }
+}
+
+/// HID state negotiated with the connected host.
+///
+/// The protocol mode and suspend state come from the BLE host and are reset whenever a new
+/// connection starts. The LEDs come from the host reports are routed to, which may be USB.
+pub struct HidState {
+    protocol_mode: AtomicU8,
+    suspended: AtomicBool,
+    leds: AtomicU8,
+    leds_changed: Signal<CriticalSectionRawMutex, Leds>,
+}
+
+pub static HID_STATE: HidState = HidState::new();
+
+impl Default for HidState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HidState {
+    pub const fn new() -> Self {
+        Self {
+            protocol_mode: AtomicU8::new(ProtocolMode::Report as u8),
+            suspended: AtomicBool::new(false),
+            leds: AtomicU8::new(0),
+            leds_changed: Signal::new(),
+        }
+    }
+
+    /// Restores the defaults a BLE host expects on a fresh connection. The LEDs are only
+    /// cleared with `clear_leds`, since they may belong to the USB host.
+    pub fn reset(&self) {
+        self.set_protocol_mode(ProtocolMode::Report);
+        self.set_suspended(false);
+    }
+
+    pub fn clear_leds(&self) {
+        self.set_leds(Leds::default());
+    }
+
+    pub fn protocol_mode(&self) -> ProtocolMode {
+        ProtocolMode::from_u8(self.protocol_mode.load(Ordering::Relaxed))
+            .unwrap_or(ProtocolMode::Report)
+    }
+
+    pub fn set_protocol_mode(&self, mode: ProtocolMode) {
+        self.protocol_mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
+
+    pub fn set_suspended(&self, suspended: bool) {
+        self.suspended.store(suspended, Ordering::Relaxed);
+    }
+
+    pub fn leds(&self) -> Leds {
+        Leds(self.leds.load(Ordering::Relaxed))
+    }
+
+    pub fn set_leds(&self, leds: Leds) {
+        if Leds(self.leds.swap(leds.0, Ordering::Relaxed)) != leds {
+            self.leds_changed.signal(leds);
+        }
+    }
+
+    /// Waits until the host changes the LED state.
+    pub async fn wait_leds(&self) -> Leds {
+        self.leds_changed.wait().await
+    }
+}
@@ -0,0 +1,98 @@
+use super::mouse::MouseAction;
+use super::tap_dance::TapDance;
+use super::tap_hold::TapHold;
+use defmt::Format;
+use usbd_human_interface_device::page::{Consumer, Desktop, Keyboard};
+
+/// What a single position of a layer does when it is pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Sends the keycode for as long as the key is held.
+    Key(Keyboard),
+    /// Sends a consumer control usage like volume or play/pause while the key is held.
+    Consumer(Consumer),
+    /// Sends a system control usage like sleep or wake while the key is held.
+    System(Desktop),
+    /// Presses a mouse button, moves the pointer or scrolls while the key is held.
+    Mouse(MouseAction),
+    /// Activates the layer while the key is held.
+    MomentaryLayer(u8),
+    /// Flips the layer on or off on every press.
+    ToggleLayer(u8),
+    /// Activates the layer for the next key press only, or acts as `MomentaryLayer` when held.
+    OneShotLayer(u8),
+    /// Replaces the base layer that all other layers fall through to.
+    DefaultLayer(u8),
+    /// Sends one keycode when tapped and a modifier or layer when held.
+    TapHold(TapHold),
+    /// Does something different depending on the number of taps and whether the last one is
+    /// held.
+    TapDance(TapDance),
+    /// Switches between the stored BLE hosts.
+    Host(HostAction),
+    /// Selects whether reports go over USB or BLE.
+    Transport(Transport),
+    /// Erases all stored settings and bonds, then restarts. Only goes off once held for
+    /// `FACTORY_RESET_HOLD`, so it can't be hit by accident.
+    FactoryReset,
+    /// Starts a leader sequence, the following keys are matched against the configured
+    /// sequences instead of being sent. Only works as a key of a layer.
+    Leader,
+    /// Plays the macro with the given ID, see `Keymap::with_macros`.
+    Macro(u8),
+    /// Records the keys typed from now on as the macro with the given ID, replacing it. Pressing
+    /// it again while recording stops the recording. Only IDs in `RECORDED_MACROS` can be
+    /// recorded. Only keyboard keys are recorded, consumer, system and mouse keys do nothing
+    /// while recording.
+    RecordMacro(u8),
+    /// Stops recording a macro and stores it.
+    StopRecording,
+    /// Uses the action of the next active layer below.
+    Transparent,
+    /// Does nothing.
+    NoOp,
+}
+
+impl Action {
+    pub fn is_layer(&self) -> bool {
+        matches!(
+            self,
+            Action::MomentaryLayer(_)
+                | Action::ToggleLayer(_)
+                | Action::OneShotLayer(_)
+                | Action::DefaultLayer(_)
+        )
+    }
+}
+
+/// Host profile actions, acting on one of the BLE bond slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HostAction {
+    /// Make the slot the active host and reconnect to it.
+    Select(u8),
+    /// Forget the bond stored in the active slot.
+    Clear,
+    /// Forget the bond stored in the active slot and advertise so a new host can pair into it.
+    Pair,
+}
+
+/// Where keyboard reports are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Transport {
+    /// USB while the keyboard is plugged into a host, BLE otherwise.
+    Auto,
+    /// Always USB, reports are dropped while unplugged.
+    Usb,
+    /// Always BLE, even while plugged in.
+    Ble,
+}
+
+/// Actions the keymap can't carry out on its own and hands to the rest of the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Command {
+    Host(HostAction),
+    Transport(Transport),
+    FactoryReset,
+    /// A macro was recorded and should be stored.
+    SaveMacro(u8),
+}
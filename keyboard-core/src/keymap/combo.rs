@@ -0,0 +1,483 @@
+use super::action::Action;
+use crate::matrix::KeyEvent;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use embassy_time::{Duration, Instant};
+
+/// Most keys a combo can have, as held keys are tracked in a `u32`.
+pub const MAX_COMBO_KEYS: usize = 32;
+
+/// A set of positions that sends `action` instead of their own actions when pressed together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Combo {
+    /// `(row, col)` of every key of the combo.
+    pub keys: &'static [(u8, u8)],
+    pub action: Action,
+    /// Time from the first to the last key press, `ComboConfig::window` if None.
+    pub timeout: Option<Duration>,
+    /// Layers the combo works on, checked against the highest active layer. Empty means all.
+    pub layers: &'static [u8],
+}
+
+impl Combo {
+    fn contains(&self, row: u8, col: u8) -> bool {
+        self.keys.contains(&(row, col))
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=MAX_COMBO_KEYS).contains(&self.keys.len())
+    }
+}
+
+/// When a combo that was sent is released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComboRelease {
+    /// As soon as any of its keys is released.
+    AnyKey,
+    /// Once all of its keys are released.
+    AllKeys,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComboConfig {
+    /// Default time from the first to the last key press of a combo.
+    pub window: Duration,
+    pub release: ComboRelease,
+}
+
+impl Default for ComboConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(50),
+            release: ComboRelease::AnyKey,
+        }
+    }
+}
+
+/// What the combo stage hands on to the keymap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ComboEvent {
+    /// A matrix event that isn't part of a combo, with the time it happened at.
+    Key(KeyEvent, Instant),
+    Press(Action),
+    Release(Action),
+}
+
+/// A combo that was sent and still has keys held down.
+#[derive(Debug, Clone, Copy)]
+struct Active {
+    combo: usize,
+    /// One bit per entry of `Combo::keys` that is still held.
+    held: u32,
+    released: bool,
+}
+
+/// Sits between the matrix and the keymap, holding back presses of combo keys until it is
+/// clear whether they form a combo.
+///
+/// While a press could still start a combo it is buffered together with the following presses.
+/// The buffer resolves once no combo can match anymore, a key is released or the timeouts
+/// pass. The largest combo that was completed in time is sent, everything else in the
+/// buffer is replayed as ordinary key events in the original order and with the original times.
+#[derive(Debug, Default)]
+pub(super) struct Combos {
+    combos: Vec<Combo>,
+    config: ComboConfig,
+    /// Presses that may still become part of a combo.
+    buffer: Vec<(KeyEvent, Instant)>,
+    /// Combo that exactly matched the first presses of `buffer`, while a larger one could
+    /// still match. Holds the combo and the number of presses it covers.
+    completed: Option<(usize, usize)>,
+    active: Vec<Active>,
+    events: VecDeque<ComboEvent>,
+}
+
+impl Combos {
+    /// Combos without keys or with more than `MAX_COMBO_KEYS` keys are left out.
+    pub(super) fn new(combos: &'static [Combo], config: ComboConfig) -> Self {
+        Self {
+            combos: combos
+                .iter()
+                .filter(|combo| combo.is_valid())
+                .copied()
+                .collect(),
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Takes the oldest event for the keymap.
+    pub(super) fn next_event(&mut self) -> Option<ComboEvent> {
+        self.events.pop_front()
+    }
+
+    /// The time at which `tick` has to be called to resolve buffered presses, when no combo
+    /// can be completed in time anymore.
+    pub(super) fn next_deadline(&self, layer: u8) -> Option<Instant> {
+        let (_, started) = *self.buffer.first()?;
+        self.candidates(layer, None, None)
+            .map(|idx| started + self.timeout(idx))
+            .max()
+            .or(Some(started))
+    }
+
+    /// Handles a matrix event at `now`, `layer` is the highest active layer.
+    pub(super) fn event(&mut self, event: KeyEvent, now: Instant, layer: u8) {
+        self.tick(now, layer);
+
+        if event.pressed {
+            self.press(event, now, layer);
+        } else {
+            self.release(event, now);
+        }
+    }
+
+    /// Resolves the buffered presses once no combo can be completed in time anymore.
+    pub(super) fn tick(&mut self, now: Instant, layer: u8) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if self.candidates(layer, None, Some(now)).next().is_none() {
+            self.resolve();
+        }
+    }
+
+    fn press(&mut self, event: KeyEvent, now: Instant, layer: u8) {
+        let position = (event.row, event.col);
+        if self
+            .candidates(layer, Some(position), Some(now))
+            .next()
+            .is_none()
+        {
+            if self.buffer.is_empty() {
+                self.events.push_back(ComboEvent::Key(event, now));
+                return;
+            }
+            // The press can't extend any combo, so the buffer is settled. It may start a new one.
+            self.resolve();
+            return self.press(event, now, layer);
+        }
+
+        self.buffer.push((event, now));
+
+        let len = self.buffer.len();
+        let exact = self
+            .candidates(layer, None, Some(now))
+            .find(|idx| self.combos[*idx].keys.len() == len);
+        let larger = self
+            .candidates(layer, None, Some(now))
+            .any(|idx| self.combos[idx].keys.len() > len);
+
+        match (exact, larger) {
+            (Some(idx), false) => {
+                self.completed = Some((idx, len));
+                self.resolve();
+            }
+            (Some(idx), true) => self.completed = Some((idx, len)),
+            (None, _) => {}
+        }
+    }
+
+    fn release(&mut self, event: KeyEvent, now: Instant) {
+        let position = (event.row, event.col);
+
+        // Any release settles the buffer, so the keymap sees it after the presses before it.
+        if !self.buffer.is_empty() {
+            self.resolve();
+        }
+
+        let combos = &self.combos;
+        let release = self.config.release;
+        let mut swallowed = false;
+
+        for active in self.active.iter_mut() {
+            let keys = combos[active.combo].keys;
+            let Some(bit) = keys.iter().position(|key| *key == position) else {
+                continue;
+            };
+            if active.held & (1 << bit) == 0 {
+                continue;
+            }
+
+            active.held &= !(1 << bit);
+            swallowed = true;
+
+            let release_now = match release {
+                ComboRelease::AnyKey => true,
+                ComboRelease::AllKeys => active.held == 0,
+            };
+            if release_now && !active.released {
+                active.released = true;
+                self.events
+                    .push_back(ComboEvent::Release(combos[active.combo].action));
+            }
+            break;
+        }
+        self.active.retain(|active| active.held != 0);
+
+        // The other keys of a combo were never pressed as far as the keymap knows.
+        if !swallowed {
+            self.events.push_back(ComboEvent::Key(event, now));
+        }
+    }
+
+    /// Sends the completed combo, if any, and replays the rest of the buffer as key events.
+    fn resolve(&mut self) {
+        let buffer = core::mem::take(&mut self.buffer);
+        let mut replay = buffer.as_slice();
+
+        if let Some((idx, len)) = self.completed.take() {
+            let combo = &self.combos[idx];
+            self.active.push(Active {
+                combo: idx,
+                held: u32::MAX >> (32 - combo.keys.len()),
+                released: false,
+            });
+            self.events.push_back(ComboEvent::Press(combo.action));
+            replay = &buffer[len..];
+        }
+
+        for (event, time) in replay {
+            self.events.push_back(ComboEvent::Key(*event, *time));
+        }
+    }
+
+    /// Combos on `layer` that contain every buffered key and `extra`, if given. With `now`
+    /// only those that can still be completed in time.
+    fn candidates(
+        &self,
+        layer: u8,
+        extra: Option<(u8, u8)>,
+        now: Option<Instant>,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let started = self.buffer.first().map(|(_, time)| *time).or(now);
+        self.combos
+            .iter()
+            .enumerate()
+            .filter(move |(_, combo)| combo.layers.is_empty() || combo.layers.contains(&layer))
+            .filter(move |(idx, _)| match (now, started) {
+                (Some(now), Some(started)) => now < started + self.timeout(*idx),
+                _ => true,
+            })
+            .filter(move |(_, combo)| {
+                self.buffer
+                    .iter()
+                    .map(|(e, _)| (e.row, e.col))
+                    .chain(extra)
+                    .all(|(row, col)| combo.contains(row, col))
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    fn timeout(&self, idx: usize) -> Duration {
+        self.combos[idx].timeout.unwrap_or(self.config.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Keymap, Layer, Report, FACTORY_RESET_HOLD};
+    use super::*;
+    use crate::keymap::tap_dance::{TapDance, TapDanceConfig};
+    use alloc::{vec, vec::Vec};
+    use usbd_human_interface_device::page::Keyboard::{self, *};
+
+    const fn combo(keys: &'static [(u8, u8)], action: Action) -> Combo {
+        Combo {
+            keys,
+            action,
+            timeout: None,
+            layers: &[],
+        }
+    }
+
+    const ESCAPE: Action = Action::Key(Escape);
+    const ENTER: Action = Action::Key(ReturnEnter);
+
+    static PAIR: [Combo; 1] = [combo(&[(0, 0), (0, 1)], ESCAPE)];
+    static OVERLAPPING: [Combo; 2] = [
+        combo(&[(0, 0), (0, 1)], ESCAPE),
+        combo(&[(0, 0), (0, 1), (0, 2)], ENTER),
+    ];
+    static ON_LAYER_1: [Combo; 1] = [Combo {
+        layers: &[1],
+        ..combo(&[(0, 0), (0, 1)], ESCAPE)
+    }];
+    static INVALID: [Combo; 2] = [combo(&[], ESCAPE), combo(&[(0, 0); 33], ENTER)];
+
+    fn key(col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            row: 0,
+            col,
+            pressed,
+        }
+    }
+
+    /// Replays `(ms, col, pressed)` events on `layer` and returns what the keymap gets.
+    fn run(
+        combos: &'static [Combo],
+        release: ComboRelease,
+        layer: u8,
+        events: &[(u64, u8, bool)],
+    ) -> Vec<ComboEvent> {
+        let config = ComboConfig {
+            release,
+            ..Default::default()
+        };
+        let mut stage = Combos::new(combos, config);
+        for &(ms, col, pressed) in events {
+            let now = Instant::from_millis(ms);
+            while let Some(deadline) = stage.next_deadline(layer).filter(|d| *d <= now) {
+                stage.tick(deadline, layer);
+            }
+            stage.event(key(col, pressed), now, layer);
+        }
+        while let Some(deadline) = stage.next_deadline(layer) {
+            stage.tick(deadline, layer);
+        }
+        core::iter::from_fn(|| stage.next_event()).collect()
+    }
+
+    fn key_at(ms: u64, col: u8, pressed: bool) -> ComboEvent {
+        ComboEvent::Key(key(col, pressed), Instant::from_millis(ms))
+    }
+
+    #[test]
+    fn pressed_within_window() {
+        let events = [(0, 0, true), (20, 1, true), (60, 0, false), (70, 1, false)];
+        let out = run(&PAIR, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(
+            out,
+            [ComboEvent::Press(ESCAPE), ComboEvent::Release(ESCAPE)]
+        );
+    }
+
+    #[test]
+    fn window_timeout_replays_keys() {
+        let events = [(0, 0, true), (60, 1, true), (80, 0, false), (90, 1, false)];
+        let out = run(&PAIR, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(
+            out,
+            [
+                key_at(0, 0, true),
+                key_at(60, 1, true),
+                key_at(80, 0, false),
+                key_at(90, 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn larger_overlapping_combo_wins() {
+        let events = [(0, 0, true), (10, 1, true), (20, 2, true), (30, 2, false)];
+        let out = run(&OVERLAPPING, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(out, [ComboEvent::Press(ENTER), ComboEvent::Release(ENTER)]);
+    }
+
+    #[test]
+    fn smaller_overlapping_combo_after_timeout() {
+        let events = [(0, 0, true), (10, 1, true), (100, 1, false)];
+        let out = run(&OVERLAPPING, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(
+            out,
+            [ComboEvent::Press(ESCAPE), ComboEvent::Release(ESCAPE)]
+        );
+    }
+
+    #[test]
+    fn other_key_settles_smaller_combo() {
+        let events = [(0, 0, true), (10, 1, true), (20, 3, true)];
+        let out = run(&OVERLAPPING, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(out, [ComboEvent::Press(ESCAPE), key_at(20, 3, true)]);
+    }
+
+    #[test]
+    fn restricted_to_layer() {
+        let events = [(0, 0, true), (10, 1, true)];
+        let out = run(&ON_LAYER_1, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(out, [key_at(0, 0, true), key_at(10, 1, true)]);
+
+        let out = run(&ON_LAYER_1, ComboRelease::AnyKey, 1, &events);
+        assert_eq!(out, [ComboEvent::Press(ESCAPE)]);
+    }
+
+    #[test]
+    fn any_key_releases_on_first_release() {
+        let events = [(0, 0, true), (10, 1, true), (50, 0, false), (60, 3, true)];
+        let out = run(&PAIR, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(
+            out,
+            [
+                ComboEvent::Press(ESCAPE),
+                ComboEvent::Release(ESCAPE),
+                key_at(60, 3, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn all_keys_releases_on_last_release() {
+        let events = [(0, 0, true), (10, 1, true), (50, 0, false), (60, 3, true)];
+        let out = run(&PAIR, ComboRelease::AllKeys, 0, &events);
+        assert_eq!(out, [ComboEvent::Press(ESCAPE), key_at(60, 3, true)]);
+
+        let events = [(0, 0, true), (10, 1, true), (50, 0, false), (60, 1, false)];
+        let out = run(&PAIR, ComboRelease::AllKeys, 0, &events);
+        assert_eq!(
+            out,
+            [ComboEvent::Press(ESCAPE), ComboEvent::Release(ESCAPE)]
+        );
+    }
+
+    #[test]
+    fn invalid_combos_are_left_out() {
+        let events = [(0, 0, true), (10, 0, false)];
+        let out = run(&INVALID, ComboRelease::AnyKey, 0, &events);
+        assert_eq!(out, [key_at(0, 0, true), key_at(10, 0, false)]);
+    }
+
+    #[test]
+    fn combo_interrupts_dance() {
+        static DANCE: [Layer<1, 3>; 1] = [[[
+            Action::TapDance(TapDance {
+                taps: &[Action::Key(Q), Action::Key(W)],
+                holds: &[],
+            }),
+            Action::Key(A),
+            Action::Key(S),
+        ]]];
+        static COMBO: [Combo; 1] = [combo(&[(0, 1), (0, 2)], ESCAPE)];
+
+        let mut keymap = Keymap::new(&DANCE)
+            .with_tap_dance(TapDanceConfig::default())
+            .with_combos(&COMBO, ComboConfig::default());
+        for (ms, col, pressed) in [(0, 0, true), (10, 0, false), (20, 1, true), (30, 2, true)] {
+            keymap.event(key(col, pressed), Instant::from_millis(ms));
+        }
+
+        let reports: Vec<Vec<Keyboard>> = core::iter::from_fn(|| keymap.next_report())
+            .filter_map(|report| match report {
+                Report::Keyboard(keys) => Some(keys.iter().collect()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reports, [vec![Q], vec![], vec![Escape]]);
+    }
+
+    #[test]
+    fn combo_action_starts_at_press_time() {
+        static KEYS: [Layer<1, 2>; 1] = [[[Action::Key(A), Action::Key(S)]]];
+        static COMBO: [Combo; 1] = [combo(&[(0, 0), (0, 1)], Action::FactoryReset)];
+
+        // No tick before the combo, so the keymap hasn't seen the current time otherwise.
+        let mut keymap = Keymap::new(&KEYS).with_combos(&COMBO, ComboConfig::default());
+        for (ms, col) in [(10_000, 0), (10_010, 1)] {
+            keymap.event(key(col, true), Instant::from_millis(ms));
+        }
+
+        assert_eq!(
+            keymap.next_deadline(),
+            Some(Instant::from_millis(10_010) + FACTORY_RESET_HOLD)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use defmt::Format;
+
+/// The highest number of layers a keymap can have.
+pub const MAX_LAYERS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+struct OneShot {
+    layer: u8,
+    /// The one-shot key itself is still held down.
+    held: bool,
+    /// Another key was pressed while the layer was active.
+    used: bool,
+}
+
+/// Tracks which layers are currently active.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct LayerState {
+    default: u8,
+    toggled: u32,
+    /// Number of momentary keys holding each layer, so overlapping holds don't cancel each other.
+    held: [u8; MAX_LAYERS],
+    oneshot: Option<OneShot>,
+}
+
+impl Default for LayerState {
+    fn default() -> Self {
+        Self {
+            default: 0,
+            toggled: 0,
+            held: [0; MAX_LAYERS],
+            oneshot: None,
+        }
+    }
+}
+
+impl LayerState {
+    pub fn default_layer(&self) -> u8 {
+        self.default
+    }
+
+    pub fn is_active(&self, layer: u8) -> bool {
+        let idx = layer as usize;
+        if idx >= MAX_LAYERS {
+            return false;
+        }
+
+        layer == self.default
+            || self.toggled & (1 << idx) != 0
+            || self.held[idx] > 0
+            || self.oneshot.is_some_and(|o| o.layer == layer)
+    }
+
+    /// Returns the active layers from the highest to the lowest. Layers below the default
+    /// layer are included while they are held, toggled or one-shot.
+    pub fn active_layers(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..MAX_LAYERS as u8)
+            .rev()
+            .filter(|layer| self.is_active(*layer))
+    }
+
+    pub fn set_default(&mut self, layer: u8) {
+        if (layer as usize) < MAX_LAYERS {
+            self.default = layer;
+        }
+    }
+
+    pub fn toggle(&mut self, layer: u8) {
+        if (layer as usize) < MAX_LAYERS {
+            self.toggled ^= 1 << layer;
+        }
+    }
+
+    pub fn hold(&mut self, layer: u8) {
+        if let Some(count) = self.held.get_mut(layer as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    pub fn release(&mut self, layer: u8) {
+        if let Some(count) = self.held.get_mut(layer as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub fn oneshot_press(&mut self, layer: u8) {
+        self.oneshot = Some(OneShot {
+            layer,
+            held: true,
+            used: false,
+        });
+    }
+
+    pub fn oneshot_release(&mut self, layer: u8) {
+        match self.oneshot {
+            Some(ref mut oneshot) if oneshot.layer == layer => {
+                if oneshot.used {
+                    self.oneshot = None;
+                } else {
+                    oneshot.held = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Called after another key has been resolved, consuming a pending one-shot layer.
+    pub fn oneshot_consume(&mut self) {
+        match self.oneshot {
+            Some(ref mut oneshot) if oneshot.held => oneshot.used = true,
+            Some(_) => self.oneshot = None,
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn default_layer_is_active() {
+        let mut state = LayerState::default();
+        state.set_default(2);
+        assert_eq!(state.active_layers().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn layers_below_default_can_be_held_and_toggled() {
+        let mut state = LayerState::default();
+        state.set_default(2);
+        state.hold(1);
+        state.toggle(0);
+        assert_eq!(state.active_layers().collect::<Vec<_>>(), [2, 1, 0]);
+
+        state.release(1);
+        state.toggle(0);
+        assert_eq!(state.active_layers().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn oneshot_is_consumed_by_the_next_key() {
+        let mut state = LayerState::default();
+        state.oneshot_press(1);
+        state.oneshot_release(1);
+        assert!(state.is_active(1));
+        state.oneshot_consume();
+        assert!(!state.is_active(1));
+    }
+}
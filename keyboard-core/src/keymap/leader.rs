@@ -0,0 +1,218 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use embassy_time::{Duration, Instant};
+use packed_struct::PrimitiveEnum;
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::page::Keyboard;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaderConfig {
+    /// Time between two keys of a sequence before it ends.
+    pub timeout: Duration,
+    /// Longest sequence, longer ones are ignored.
+    pub max_len: usize,
+    /// Type the keys of a sequence that matched nothing instead of dropping them.
+    pub replay_unmatched: bool,
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(1000),
+            max_len: 4,
+            replay_unmatched: false,
+        }
+    }
+}
+
+/// What a leader sequence sends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LeaderOutput {
+    /// Keyboard usages pressed together and released again, e.g. a shortcut.
+    Chord(Vec<u8>),
+    /// Typed character by character on a US layout, see `text::ascii_key`.
+    Text(String),
+    /// Plays the macro with the given ID.
+    Macro(u8),
+}
+
+/// Keys typed after the leader key and what they send. Keys are stored as keyboard usages so
+/// sequences can be kept in the KV store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaderSequence {
+    pub keys: Vec<u8>,
+    pub output: LeaderOutput,
+}
+
+impl LeaderSequence {
+    pub fn chord(keys: &[Keyboard], chord: &[Keyboard]) -> Self {
+        Self {
+            keys: usages(keys),
+            output: LeaderOutput::Chord(usages(chord)),
+        }
+    }
+
+    pub fn text(keys: &[Keyboard], text: &str) -> Self {
+        Self {
+            keys: usages(keys),
+            output: LeaderOutput::Text(text.to_string()),
+        }
+    }
+
+    pub fn play(keys: &[Keyboard], id: u8) -> Self {
+        Self {
+            keys: usages(keys),
+            output: LeaderOutput::Macro(id),
+        }
+    }
+}
+
+fn usages(keys: &[Keyboard]) -> Vec<u8> {
+    keys.iter().map(|key| key.to_primitive()).collect()
+}
+
+/// How a leader sequence ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Finish {
+    Output(LeaderOutput),
+    /// Nothing matched, the typed keys are sent as taps.
+    Replay(Vec<Keyboard>),
+    /// Nothing matched and nothing is sent.
+    Cancel,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: Vec<(u8, usize)>,
+    /// Index into `Leader::sequences` of the sequence ending here.
+    output: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Active {
+    node: usize,
+    typed: Vec<Keyboard>,
+    deadline: Instant,
+}
+
+/// Matches the keys typed after the leader key against a trie of the configured sequences.
+#[derive(Debug)]
+pub(super) struct Leader {
+    config: LeaderConfig,
+    sequences: Vec<LeaderSequence>,
+    /// The trie, the root is the first node.
+    nodes: Vec<Node>,
+    active: Option<Active>,
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Self::new(LeaderConfig::default(), Vec::new())
+    }
+}
+
+impl Leader {
+    pub(super) fn new(config: LeaderConfig, sequences: Vec<LeaderSequence>) -> Self {
+        let mut nodes = Vec::from([Node::default()]);
+
+        for (idx, sequence) in sequences.iter().enumerate() {
+            if sequence.keys.is_empty() || sequence.keys.len() > config.max_len {
+                continue;
+            }
+
+            let mut node = 0;
+            for &key in &sequence.keys {
+                node = match nodes[node].children.iter().find(|(k, _)| *k == key) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(Node::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((key, child));
+                        child
+                    }
+                };
+            }
+            // The first of two sequences with the same keys wins.
+            nodes[node].output.get_or_insert(idx);
+        }
+
+        Self {
+            config,
+            sequences,
+            nodes,
+            active: None,
+        }
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        self.active.as_ref().map(|active| active.deadline)
+    }
+
+    /// Starts a new sequence, dropping the one in progress.
+    pub(super) fn start(&mut self, now: Instant) {
+        self.active = Some(Active {
+            node: 0,
+            typed: Vec::new(),
+            deadline: now + self.config.timeout,
+        });
+    }
+
+    /// Adds a key to the sequence, returns how it ended if this key decided it.
+    pub(super) fn key(&mut self, key: Keyboard, now: Instant) -> Option<Finish> {
+        let active = self.active.as_mut()?;
+        active.typed.push(key);
+
+        let usage = key.to_primitive();
+        let child = self.nodes[active.node]
+            .children
+            .iter()
+            .find(|(k, _)| *k == usage)
+            .map(|(_, child)| *child);
+
+        match child {
+            Some(child) => {
+                active.node = child;
+                active.deadline = now + self.config.timeout;
+                let leaf = self.nodes[child].children.is_empty();
+                (leaf || active.typed.len() >= self.config.max_len).then(|| self.finish())
+            }
+            None => {
+                // A complete sequence followed by a key that extends nothing doesn't count.
+                active.node = 0;
+                Some(self.finish())
+            }
+        }
+    }
+
+    /// Ends the sequence early, because a key that can't be part of it was pressed.
+    pub(super) fn cancel(&mut self) -> Finish {
+        if let Some(active) = self.active.as_mut() {
+            active.node = 0;
+        }
+        self.finish()
+    }
+
+    /// Ends the sequence if no key was typed in time.
+    pub(super) fn tick(&mut self, now: Instant) -> Option<Finish> {
+        let deadline = self.active.as_ref()?.deadline;
+        (now >= deadline).then(|| self.finish())
+    }
+
+    fn finish(&mut self) -> Finish {
+        let Some(active) = self.active.take() else {
+            return Finish::Cancel;
+        };
+
+        match self.nodes[active.node].output {
+            Some(idx) => Finish::Output(self.sequences[idx].output.clone()),
+            _ if self.config.replay_unmatched && !active.typed.is_empty() => {
+                Finish::Replay(active.typed)
+            }
+            _ => Finish::Cancel,
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use super::{text, KeySet};
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ops::Range;
+use embassy_time::{Duration, Instant};
+use packed_struct::PrimitiveEnum;
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::page::Keyboard;
+
+/// Number of macro IDs, `Action::Macro` with a higher ID does nothing.
+pub const MAX_MACROS: usize = 16;
+
+/// IDs `Action::RecordMacro` can record to. The IDs below are left to the macros of the
+/// layout, so a recording can't replace them.
+pub const RECORDED_MACROS: Range<u8> = 8..MAX_MACROS as u8;
+
+/// Most steps a recorded macro can have, recording stops once it is reached. Keeps a
+/// recording to about 1 KB of the heap.
+pub const MAX_RECORDED_STEPS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacroConfig {
+    /// Time between two reports of a macro. Reports sent faster than the BLE connection
+    /// interval may be dropped by the host.
+    pub interval: Duration,
+}
+
+impl Default for MacroConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(15),
+        }
+    }
+}
+
+/// A single step of a macro. Keys are stored as keyboard usages so macros can be kept in the
+/// KV store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacroStep {
+    /// Holds the key until a `Release` step or the end of the macro.
+    Press(u8),
+    Release(u8),
+    /// Presses and releases the key.
+    Tap(u8),
+    /// Waits for the given number of milliseconds.
+    Delay(u16),
+    /// Types the text on a US layout, see `text::ascii_key`. Held modifiers are left out of
+    /// these reports, so Shift being held doesn't change the text.
+    Text(String),
+}
+
+impl MacroStep {
+    pub fn press(key: Keyboard) -> Self {
+        MacroStep::Press(key.to_primitive())
+    }
+
+    pub fn release(key: Keyboard) -> Self {
+        MacroStep::Release(key.to_primitive())
+    }
+
+    pub fn tap(key: Keyboard) -> Self {
+        MacroStep::Tap(key.to_primitive())
+    }
+
+    pub fn text(text: &str) -> Self {
+        MacroStep::Text(text.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Macro {
+    pub steps: Vec<MacroStep>,
+}
+
+impl Macro {
+    pub fn new(steps: Vec<MacroStep>) -> Self {
+        Self { steps }
+    }
+}
+
+/// One report worth of change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Frame {
+    Press(Keyboard),
+    Release(Keyboard),
+    /// A key of a `Text` step, pressed or released together with Shift if needed.
+    Char {
+        key: Keyboard,
+        shift: bool,
+        pressed: bool,
+    },
+    /// The macro is done, keys it still holds are released.
+    End,
+}
+
+/// The steps a cursor walks through.
+#[derive(Debug, Clone)]
+enum Source {
+    /// The stored macro with this ID.
+    Stored(u8),
+    /// Steps that are only played once, e.g. the output of a leader sequence.
+    Steps(Vec<MacroStep>),
+}
+
+/// Position in a macro that is being played.
+#[derive(Debug, Clone)]
+struct Cursor {
+    source: Source,
+    step: usize,
+    /// Byte offset into the text of a `Text` step.
+    offset: usize,
+    /// The key of the current `Tap` or character is pressed and has to be released next.
+    releasing: bool,
+}
+
+enum Next {
+    Frame(Frame),
+    Wait(Duration),
+}
+
+impl Cursor {
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            step: 0,
+            offset: 0,
+            releasing: false,
+        }
+    }
+
+    /// Advances to the next frame or delay, None at the end of the macro.
+    fn next(&mut self, macros: &[Macro]) -> Option<Next> {
+        let Cursor {
+            source,
+            step: index,
+            offset,
+            releasing,
+        } = self;
+        let steps = match source {
+            Source::Stored(id) => &macros.get(*id as usize)?.steps,
+            Source::Steps(steps) => steps,
+        };
+
+        loop {
+            let step = steps.get(*index)?;
+
+            match step {
+                MacroStep::Press(usage) | MacroStep::Release(usage) => {
+                    *index += 1;
+                    let Some(key) = Keyboard::from_primitive(*usage) else {
+                        continue;
+                    };
+                    return Some(Next::Frame(match step {
+                        MacroStep::Press(_) => Frame::Press(key),
+                        _ => Frame::Release(key),
+                    }));
+                }
+                MacroStep::Tap(usage) => {
+                    let Some(key) = Keyboard::from_primitive(*usage) else {
+                        *index += 1;
+                        continue;
+                    };
+                    if *releasing {
+                        *releasing = false;
+                        *index += 1;
+                        return Some(Next::Frame(Frame::Release(key)));
+                    }
+                    *releasing = true;
+                    return Some(Next::Frame(Frame::Press(key)));
+                }
+                MacroStep::Delay(ms) => {
+                    *index += 1;
+                    return Some(Next::Wait(Duration::from_millis(*ms as u64)));
+                }
+                MacroStep::Text(text) => {
+                    let Some(c) = text[*offset..].chars().next() else {
+                        *index += 1;
+                        *offset = 0;
+                        continue;
+                    };
+                    let Some((key, shift)) = text::ascii_key(c) else {
+                        *offset += c.len_utf8();
+                        continue;
+                    };
+                    if *releasing {
+                        *releasing = false;
+                        *offset += c.len_utf8();
+                    } else {
+                        *releasing = true;
+                    }
+                    return Some(Next::Frame(Frame::Char {
+                        key,
+                        shift,
+                        pressed: *releasing,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// Plays macros one report at a time, `MacroConfig::interval` apart. Macros started while
+/// another one is playing are queued behind it.
+#[derive(Debug, Default)]
+pub(super) struct Player {
+    config: MacroConfig,
+    queue: VecDeque<Cursor>,
+    /// When the next frame is due, None while nothing is playing.
+    deadline: Option<Instant>,
+}
+
+impl Player {
+    pub(super) fn new(config: MacroConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(super) fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Queues macro `id`, it starts with the next tick if nothing else is playing.
+    pub(super) fn start(&mut self, id: u8, now: Instant) {
+        self.queue(Source::Stored(id), now);
+    }
+
+    /// Queues steps that aren't stored as a macro, so they are paced like one.
+    pub(super) fn play(&mut self, steps: Vec<MacroStep>, now: Instant) {
+        self.queue(Source::Steps(steps), now);
+    }
+
+    fn queue(&mut self, source: Source, now: Instant) {
+        if self.deadline.is_none() {
+            self.deadline = Some(now);
+        }
+        self.queue.push_back(Cursor::new(source));
+    }
+
+    /// The next frame if it is due by `now`.
+    pub(super) fn tick(&mut self, now: Instant, macros: &[Macro]) -> Option<Frame> {
+        loop {
+            if now < self.deadline? {
+                return None;
+            }
+            let Some(cursor) = self.queue.front_mut() else {
+                self.deadline = None;
+                return None;
+            };
+
+            match cursor.next(macros) {
+                Some(Next::Frame(frame)) => {
+                    self.deadline = Some(now + self.config.interval);
+                    return Some(frame);
+                }
+                Some(Next::Wait(delay)) => self.deadline = Some(now + delay),
+                None => {
+                    self.queue.pop_front();
+                    self.deadline = Some(now + self.config.interval);
+                    return Some(Frame::End);
+                }
+            }
+        }
+    }
+}
+
+/// A macro that is being recorded from the keyboard reports, so it holds the keys after
+/// layers, tap-hold and everything else in the keymap were applied. Consumer, system and
+/// mouse keys still reach the host while recording, but are left out of the macro.
+#[derive(Debug)]
+pub(super) struct Recording {
+    pub id: u8,
+    steps: Vec<MacroStep>,
+    /// The keys of the last recorded report.
+    keys: KeySet,
+    /// When the last step was recorded.
+    last: Instant,
+    /// Subtracted from the recorded delays, playback adds it between reports.
+    interval: Duration,
+}
+
+impl Recording {
+    pub(super) fn new(id: u8, keys: KeySet, now: Instant, interval: Duration) -> Self {
+        Self {
+            id,
+            steps: Vec::with_capacity(MAX_RECORDED_STEPS),
+            keys,
+            last: now,
+            interval,
+        }
+    }
+
+    /// Records the changes from the last report to `keys`. Returns false once the recording
+    /// is full, the changes that didn't fit are dropped.
+    pub(super) fn record(&mut self, keys: KeySet, now: Instant) -> bool {
+        let released = self.keys.iter().filter(|key| !keys.contains(*key));
+        let pressed = keys.iter().filter(|key| !self.keys.contains(*key));
+        let changes: Vec<MacroStep> = released
+            .map(MacroStep::release)
+            .chain(pressed.map(MacroStep::press))
+            .collect();
+        self.keys = keys;
+
+        // Nothing to wait for before the first step.
+        let delay = now
+            .saturating_duration_since(self.last)
+            .checked_sub(self.interval);
+        if let Some(delay) = delay.filter(|_| !self.steps.is_empty()) {
+            let ms = delay.as_millis().min(u16::MAX as u64) as u16;
+            if ms > 0 {
+                self.push(MacroStep::Delay(ms));
+            }
+        }
+        self.last = now;
+
+        changes.into_iter().all(|step| self.push(step))
+    }
+
+    fn push(&mut self, step: MacroStep) -> bool {
+        if self.steps.len() >= MAX_RECORDED_STEPS {
+            return false;
+        }
+        self.steps.push(step);
+        true
+    }
+
+    /// The recorded macro, without the room reserved for steps that weren't recorded.
+    pub(super) fn finish(mut self) -> Macro {
+        self.steps.shrink_to_fit();
+        Macro::new(self.steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use usbd_human_interface_device::page::Keyboard::*;
+
+    fn frames(player: &mut Player, macros: &[Macro]) -> Vec<(u64, Frame)> {
+        let mut frames = Vec::new();
+        while let Some(deadline) = player.next_deadline() {
+            if let Some(frame) = player.tick(deadline, macros) {
+                frames.push((deadline.as_millis(), frame));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn steps_are_paced_like_a_macro() {
+        let mut player = Player::default();
+        player.play(
+            vec![MacroStep::tap(A), MacroStep::text("B")],
+            Instant::from_millis(0),
+        );
+
+        let shifted_b = |pressed| Frame::Char {
+            key: B,
+            shift: true,
+            pressed,
+        };
+        assert_eq!(
+            frames(&mut player, &[]),
+            [
+                (0, Frame::Press(A)),
+                (15, Frame::Release(A)),
+                (30, shifted_b(true)),
+                (45, shifted_b(false)),
+                (60, Frame::End),
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_queue_behind_stored_macro() {
+        let macros = [Macro::new(vec![MacroStep::Delay(100)])];
+        let mut player = Player::default();
+        player.start(0, Instant::from_millis(0));
+        player.play(vec![MacroStep::tap(A)], Instant::from_millis(0));
+
+        assert_eq!(
+            frames(&mut player, &macros),
+            [
+                (100, Frame::End),
+                (115, Frame::Press(A)),
+                (130, Frame::Release(A)),
+                (145, Frame::End),
+            ]
+        );
+    }
+}
@@ -0,0 +1,932 @@
+pub mod action;
+pub mod combo;
+pub mod layer;
+pub mod leader;
+pub mod macros;
+pub mod mouse;
+pub mod tap_dance;
+pub mod tap_hold;
+pub mod text;
+
+use self::action::{Action, Command};
+use self::combo::{Combo, ComboConfig, ComboEvent, Combos};
+use self::layer::LayerState;
+use self::leader::{Finish, Leader, LeaderConfig, LeaderOutput, LeaderSequence};
+use self::macros::{Frame, Macro, MacroConfig, MacroStep, Player, Recording, RECORDED_MACROS};
+use self::mouse::{MouseConfig, MouseKeys, MouseReport};
+use self::tap_dance::{Dance, Outcome, TapDanceConfig};
+use self::tap_hold::{Pending, Resolution, TapHoldConfig};
+use crate::matrix::KeyEvent;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use embassy_time::{Duration, Instant};
+use packed_struct::PrimitiveEnum;
+use usbd_human_interface_device::{
+    device::keyboard::BootKeyboardReport,
+    page::{Consumer, Desktop, Keyboard},
+};
+
+/// A single layer of the keymap, indexed by `[row][col]`.
+pub type Layer<const ROWS: usize, const COLS: usize> = [[Action; COLS]; ROWS];
+
+/// How long `Action::FactoryReset` has to be held before it erases anything.
+pub const FACTORY_RESET_HOLD: Duration = Duration::from_secs(3);
+
+/// The set of keyboard usages that are currently held down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeySet([u32; 8]);
+
+impl KeySet {
+    pub fn insert(&mut self, key: Keyboard) {
+        let usage = key as u8 as usize;
+        self.0[usage / 32] |= 1 << (usage % 32);
+    }
+
+    pub fn remove(&mut self, key: Keyboard) {
+        let usage = key as u8 as usize;
+        self.0[usage / 32] &= !(1 << (usage % 32));
+    }
+
+    pub fn contains(&self, key: Keyboard) -> bool {
+        let usage = key as u8 as usize;
+        self.0[usage / 32] & (1 << (usage % 32)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    /// True if every key in `self` is also held in `other`.
+    pub fn is_subset(&self, other: &KeySet) -> bool {
+        self.0
+            .iter()
+            .zip(other.0)
+            .all(|(word, other)| word & !other == 0)
+    }
+
+    /// The same keys without LeftControl to RightGUI.
+    fn without_modifiers(mut self) -> Self {
+        (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8)
+            .filter_map(Keyboard::from_primitive)
+            .for_each(|key| self.remove(key));
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Keyboard> + '_ {
+        (0..=u8::MAX)
+            .filter(|usage| self.0[*usage as usize / 32] & (1 << (usage % 32)) != 0)
+            .filter_map(Keyboard::from_primitive)
+    }
+
+    /// Packs the held keys into a 6KRO boot report, modifiers go into the modifier byte.
+    pub fn boot_report(&self) -> BootKeyboardReport {
+        BootKeyboardReport::new(self.iter())
+    }
+}
+
+/// A change to one of the reports sent to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    /// All held keyboard keys.
+    Keyboard(KeySet),
+    /// The held consumer control usage, if any.
+    Consumer(Option<Consumer>),
+    /// The held system control usage, if any.
+    System(Option<Desktop>),
+    /// Mouse buttons and pointer movement since the last mouse report.
+    Mouse(MouseReport),
+}
+
+impl Report {
+    /// Reports releasing every key and button.
+    pub fn released() -> [Report; 4] {
+        [
+            Report::Keyboard(KeySet::default()),
+            Report::Consumer(None),
+            Report::System(None),
+            Report::Mouse(MouseReport::default()),
+        ]
+    }
+}
+
+/// The latest report of every kind, collected while the host can't receive them. Sending them
+/// once it is back brings it up to the current state without replaying what happened meanwhile,
+/// so pointer movement is dropped and only the held buttons are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatestReports([Option<Report>; 4]);
+
+impl Default for LatestReports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatestReports {
+    pub const fn new() -> Self {
+        Self([None; 4])
+    }
+
+    pub fn push(&mut self, report: Report) {
+        let (slot, report) = match report {
+            Report::Keyboard(_) => (0, report),
+            Report::Consumer(_) => (1, report),
+            Report::System(_) => (2, report),
+            Report::Mouse(mouse) => (
+                3,
+                Report::Mouse(MouseReport {
+                    buttons: mouse.buttons,
+                    ..MouseReport::default()
+                }),
+            ),
+        };
+        self.0[slot] = Some(report);
+    }
+
+    /// Takes the collected reports, leaving none behind.
+    pub fn take(&mut self) -> impl Iterator<Item = Report> {
+        core::mem::take(&mut self.0).into_iter().flatten()
+    }
+}
+
+/// Resolves matrix events against a stack of layers and tracks the resulting keyboard state.
+///
+/// Every change to the held keys is queued as a `KeySet` snapshot, so a tap that is resolved
+/// late still produces both a press and a release report. Consumer and system control keys
+/// are queued in the same order as separate reports.
+pub struct Keymap<const ROWS: usize, const COLS: usize> {
+    layers: &'static [Layer<ROWS, COLS>],
+    state: LayerState,
+    /// The action each held position resolved to when it was pressed, so the release undoes
+    /// the same action even if the active layers changed in between.
+    pressed: [[Option<Action>; COLS]; ROWS],
+    keys: KeySet,
+    /// The most recent state pushed to `reports`.
+    queued: KeySet,
+    consumer: Option<Consumer>,
+    system: Option<Desktop>,
+    mouse: MouseKeys,
+    /// Runs before everything else, turning chords of keys into combo actions.
+    combos: Combos,
+    reports: VecDeque<Report>,
+    commands: VecDeque<Command>,
+    tap_hold: TapHoldConfig,
+    /// Tap-hold key waiting for the tapping term or another event to decide what it is.
+    pending: Option<Pending>,
+    /// Events that happened while `pending` was undecided, replayed once it resolves.
+    buffer: VecDeque<(KeyEvent, Instant)>,
+    /// Tap-hold key that resolved to hold on timeout and may still retro-tap.
+    retro: Option<(u8, u8, Keyboard)>,
+    tap_dance: TapDanceConfig,
+    /// Tap dance key that is still counting taps.
+    dance: Option<Dance>,
+    leader: Leader,
+    /// Macros by ID.
+    macros: Vec<Macro>,
+    player: Player,
+    /// Keys pressed by the macro that is playing, released when it ends.
+    macro_keys: KeySet,
+    /// Keys of the character a macro is typing, Some while it types text. Held modifiers
+    /// are left out of the reports meanwhile.
+    text: Option<KeySet>,
+    /// Macro that is being recorded from the queued keyboard reports.
+    recording: Option<Recording>,
+    /// When the held `Action::FactoryReset` goes off.
+    reset: Option<Instant>,
+    /// Time of the event or tick being handled, for actions that start timers from
+    /// `activate`.
+    now: Instant,
+}
+
+impl<const ROWS: usize, const COLS: usize> Keymap<ROWS, COLS> {
+    pub fn new(layers: &'static [Layer<ROWS, COLS>]) -> Self {
+        Self::with_tap_hold(layers, TapHoldConfig::default())
+    }
+
+    pub fn with_tap_hold(layers: &'static [Layer<ROWS, COLS>], tap_hold: TapHoldConfig) -> Self {
+        Self {
+            layers,
+            state: LayerState::default(),
+            pressed: [[None; COLS]; ROWS],
+            keys: KeySet::default(),
+            queued: KeySet::default(),
+            consumer: None,
+            system: None,
+            mouse: MouseKeys::default(),
+            combos: Combos::default(),
+            reports: VecDeque::new(),
+            commands: VecDeque::new(),
+            tap_hold,
+            pending: None,
+            buffer: VecDeque::new(),
+            retro: None,
+            tap_dance: TapDanceConfig::default(),
+            dance: None,
+            leader: Leader::default(),
+            macros: Vec::new(),
+            player: Player::default(),
+            macro_keys: KeySet::default(),
+            text: None,
+            recording: None,
+            reset: None,
+            now: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn with_mouse(mut self, config: MouseConfig) -> Self {
+        self.mouse = MouseKeys::new(config);
+        self
+    }
+
+    pub fn with_tap_dance(mut self, config: TapDanceConfig) -> Self {
+        self.tap_dance = config;
+        self
+    }
+
+    pub fn with_combos(mut self, combos: &'static [Combo], config: ComboConfig) -> Self {
+        self.combos = Combos::new(combos, config);
+        self
+    }
+
+    pub fn with_leader(mut self, config: LeaderConfig, sequences: Vec<LeaderSequence>) -> Self {
+        self.leader = Leader::new(config, sequences);
+        self
+    }
+
+    /// `macros` is indexed by the ID of `Action::Macro`.
+    pub fn with_macros(mut self, config: MacroConfig, macros: Vec<Macro>) -> Self {
+        self.macros = macros;
+        self.player = Player::new(config);
+        self
+    }
+
+    pub fn layers(&self) -> &LayerState {
+        &self.state
+    }
+
+    pub fn keys(&self) -> &KeySet {
+        &self.keys
+    }
+
+    /// The macro with the given ID, e.g. to store it after `Command::SaveMacro`.
+    pub fn get_macro(&self, id: u8) -> Option<&Macro> {
+        self.macros.get(id as usize)
+    }
+
+    /// Takes the oldest report that still has to be sent to the host.
+    pub fn next_report(&mut self) -> Option<Report> {
+        self.reports.pop_front()
+    }
+
+    /// Takes the oldest command triggered by a key press.
+    pub fn next_command(&mut self) -> Option<Command> {
+        self.commands.pop_front()
+    }
+
+    /// The time at which `tick` has to be called to resolve buffered combo keys, a pending
+    /// tap-hold key, a tap dance or a leader sequence, to send the next report of a macro or
+    /// to move the mouse pointer.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.combos.next_deadline(self.top_layer()),
+            self.pending.map(|pending| pending.deadline),
+            self.dance.map(|dance| dance.deadline),
+            self.leader.next_deadline(),
+            self.player.next_deadline(),
+            self.mouse.next_deadline(),
+            self.reset,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Looks up the action for a position, falling through transparent entries.
+    pub fn action(&self, row: usize, col: usize) -> Action {
+        self.state
+            .active_layers()
+            .filter_map(|layer| self.layers.get(layer as usize))
+            .filter_map(|layer| layer.get(row).and_then(|r| r.get(col)))
+            .find(|action| **action != Action::Transparent)
+            .copied()
+            .unwrap_or(Action::NoOp)
+    }
+
+    fn top_layer(&self) -> u8 {
+        self.state
+            .active_layers()
+            .next()
+            .unwrap_or(self.state.default_layer())
+    }
+
+    /// Applies a matrix event that happened at `now`.
+    pub fn event(&mut self, event: KeyEvent, now: Instant) {
+        if event.row as usize >= ROWS || event.col as usize >= COLS {
+            return;
+        }
+
+        // Combo actions are carried out right away and start their timers now.
+        self.now = now;
+        self.combos.event(event, now, self.top_layer());
+        self.drain_combos();
+    }
+
+    /// Resolves buffered combo keys, a pending tap-hold key, a tap dance or a leader sequence
+    /// whose time has passed by `now`, sends the next report of a macro and moves the mouse
+    /// pointer if a step is due.
+    pub fn tick(&mut self, now: Instant) {
+        self.now = now;
+        self.combos.tick(now, self.top_layer());
+        self.drain_combos();
+        self.tick_pending(now);
+        self.tick_dance(now);
+        self.tick_leader(now);
+        self.tick_macro(now);
+        self.tick_mouse(now);
+        self.tick_reset(now);
+    }
+
+    fn drain_combos(&mut self) {
+        while let Some(event) = self.combos.next_event() {
+            match event {
+                ComboEvent::Key(event, time) => self.key_event(event, time),
+                ComboEvent::Press(action) => self.combo_press(action),
+                ComboEvent::Release(action) => self.release(action),
+            }
+        }
+    }
+
+    /// Applies a matrix event that passed the combo stage.
+    fn key_event(&mut self, event: KeyEvent, now: Instant) {
+        self.now = now;
+        self.tick_pending(now);
+        self.tick_dance(now);
+        self.tick_leader(now);
+        self.tick_mouse(now);
+        self.tick_reset(now);
+
+        match self.pending {
+            Some(pending) => self.wait(pending, event, now),
+            None => self.handle(event, now),
+        }
+        self.tick_mouse(now);
+    }
+
+    /// Resolves a pending tap-hold key to hold if its tapping term has passed by `now`.
+    fn tick_pending(&mut self, now: Instant) {
+        if let Some(pending) = self.pending {
+            if now >= pending.deadline {
+                if self.tap_hold.retro_tapping && self.buffer.is_empty() {
+                    self.retro = Some((pending.row, pending.col, pending.tap_hold.tap));
+                }
+                self.resolve(Resolution::Hold);
+            }
+        }
+    }
+
+    /// Ends a tap dance if its term has passed by `now` without another tap.
+    fn tick_dance(&mut self, now: Instant) {
+        if let Some(dance) = self.dance {
+            if now >= dance.deadline {
+                self.dance = None;
+                self.finish_dance(dance.row, dance.col, dance.timeout());
+            }
+        }
+    }
+
+    fn finish_dance(&mut self, row: u8, col: u8, outcome: Outcome) {
+        match outcome {
+            Outcome::Tap(action) => {
+                self.activate(action);
+                self.release(action);
+            }
+            Outcome::Hold(action) => self.press(row, col, action),
+        }
+    }
+
+    /// Ends a leader sequence if no key was typed in time.
+    fn tick_leader(&mut self, now: Instant) {
+        if let Some(finish) = self.leader.tick(now) {
+            self.finish_leader(finish);
+        }
+    }
+
+    fn finish_leader(&mut self, finish: Finish) {
+        match finish {
+            Finish::Output(LeaderOutput::Chord(usages)) => {
+                let keys: Vec<Keyboard> = usages
+                    .into_iter()
+                    .filter_map(Keyboard::from_primitive)
+                    .collect();
+                self.tap_keys(&keys);
+            }
+            // Played like a macro, so the reports don't reach the host all at once.
+            Finish::Output(LeaderOutput::Text(text)) => {
+                self.player.play([MacroStep::Text(text)].to_vec(), self.now)
+            }
+            Finish::Output(LeaderOutput::Macro(id)) => self.player.start(id, self.now),
+            Finish::Replay(keys) => {
+                let steps = keys.into_iter().map(MacroStep::tap).collect();
+                self.player.play(steps, self.now);
+            }
+            Finish::Cancel => {}
+        }
+    }
+
+    /// Presses `keys` together and releases them again. Keys that were already held stay held.
+    fn tap_keys(&mut self, keys: &[Keyboard]) {
+        let held = self.keys;
+        for key in keys {
+            self.keys.insert(*key);
+        }
+        self.snapshot();
+        for key in keys.iter().filter(|key| !held.contains(**key)) {
+            self.keys.remove(*key);
+        }
+        self.snapshot();
+    }
+
+    /// Sends the next report of the macro that is playing if it is due.
+    fn tick_macro(&mut self, now: Instant) {
+        while let Some(frame) = self.player.tick(now, &self.macros) {
+            match frame {
+                Frame::Press(key) => {
+                    self.text = None;
+                    self.macro_keys.insert(key);
+                    self.keys.insert(key);
+                }
+                Frame::Release(key) => {
+                    self.text = None;
+                    self.macro_keys.remove(key);
+                    self.keys.remove(key);
+                }
+                Frame::Char {
+                    key,
+                    shift,
+                    pressed,
+                } => {
+                    let mut text = KeySet::default();
+                    if pressed {
+                        text.insert(key);
+                        if shift {
+                            text.insert(Keyboard::LeftShift);
+                        }
+                    }
+                    self.text = Some(text);
+                }
+                Frame::End => {
+                    self.text = None;
+                    for key in self.macro_keys.iter() {
+                        self.keys.remove(key);
+                    }
+                    self.macro_keys = KeySet::default();
+                }
+            }
+            self.snapshot();
+        }
+    }
+
+    fn tick_mouse(&mut self, now: Instant) {
+        if let Some(report) = self.mouse.tick(now) {
+            self.reports.push_back(Report::Mouse(report));
+        }
+    }
+
+    fn tick_reset(&mut self, now: Instant) {
+        if self.reset.is_some_and(|deadline| now >= deadline) {
+            self.reset = None;
+            self.commands.push_back(Command::FactoryReset);
+        }
+    }
+
+    fn wait(&mut self, pending: Pending, event: KeyEvent, now: Instant) {
+        self.buffer.push_back((event, now));
+
+        if event.row == pending.row && event.col == pending.col {
+            if !event.pressed {
+                self.resolve(Resolution::Tap);
+            }
+            return;
+        }
+
+        let config = self.tap_hold;
+        let other_tapped = !event.pressed
+            && self
+                .buffer
+                .iter()
+                .any(|(e, _)| e.pressed && e.row == event.row && e.col == event.col);
+
+        if (config.hold_on_other_key_press && event.pressed)
+            || (config.permissive_hold && other_tapped)
+        {
+            self.resolve(Resolution::Hold);
+        }
+    }
+
+    fn resolve(&mut self, resolution: Resolution) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+
+        let action = match resolution {
+            Resolution::Tap => Action::Key(pending.tap_hold.tap),
+            Resolution::Hold => pending.tap_hold.hold.action(),
+        };
+        self.press(pending.row, pending.col, action);
+
+        let buffered = core::mem::take(&mut self.buffer);
+        for (event, time) in buffered {
+            self.key_event(event, time);
+        }
+    }
+
+    fn handle(&mut self, event: KeyEvent, now: Instant) {
+        let (row, col) = (event.row, event.col);
+
+        if let Some(mut dance) = self.dance {
+            if (dance.row, dance.col) == (row, col) {
+                let outcome = dance.event(event.pressed, now, self.tap_dance.term);
+                self.dance = outcome.is_none().then_some(dance);
+                if let Some(outcome) = outcome {
+                    self.finish_dance(row, col, outcome);
+                }
+                return;
+            }
+            if event.pressed {
+                self.dance = None;
+                self.finish_dance(dance.row, dance.col, dance.interrupt());
+            }
+        }
+
+        if !event.pressed {
+            if let Some(action) = self.pressed[row as usize][col as usize].take() {
+                self.release(action);
+            }
+            if let Some((r, c, tap)) = self.retro {
+                if (r, c) == (row, col) {
+                    self.retro = None;
+                    self.keys.insert(tap);
+                    self.snapshot();
+                    self.keys.remove(tap);
+                    self.snapshot();
+                }
+            }
+            return;
+        }
+
+        self.retro = None;
+        let action = self.action(row as usize, col as usize);
+        if action == Action::Leader {
+            self.state.oneshot_consume();
+            self.leader.start(now);
+            self.pressed[row as usize][col as usize] = Some(Action::NoOp);
+            return;
+        }
+        if self.leader.is_active() {
+            // Tap-hold keys take part with their tap key, layer keys work as usual so
+            // sequences can use keys of other layers. Anything else ends the sequence.
+            let key = match action {
+                Action::Key(key) => Some(key),
+                Action::TapHold(tap_hold) => Some(tap_hold.tap),
+                _ => None,
+            };
+            if let Some(key) = key {
+                self.pressed[row as usize][col as usize] = Some(Action::NoOp);
+                if let Some(finish) = self.leader.key(key, now) {
+                    self.finish_leader(finish);
+                }
+                return;
+            }
+            if !action.is_layer() {
+                let finish = self.leader.cancel();
+                self.finish_leader(finish);
+            }
+        }
+        if let Action::TapHold(tap_hold) = action {
+            self.state.oneshot_consume();
+            self.pending = Some(Pending {
+                row,
+                col,
+                tap_hold,
+                deadline: now + self.tap_hold.tapping_term,
+            });
+            return;
+        }
+        if let Action::TapDance(tap_dance) = action {
+            self.state.oneshot_consume();
+            self.dance = Some(Dance::new(row, col, tap_dance, now, self.tap_dance.term));
+            return;
+        }
+
+        self.press(row, col, action);
+    }
+
+    /// A combo counts as another key held down, so a pending tap-hold key resolves to hold
+    /// and a dance is interrupted. A leader sequence ends unless the combo changes layers.
+    fn combo_press(&mut self, action: Action) {
+        self.resolve(Resolution::Hold);
+        self.retro = None;
+        if let Some(dance) = self.dance.take() {
+            self.finish_dance(dance.row, dance.col, dance.interrupt());
+        }
+        if self.leader.is_active() && !action.is_layer() {
+            let finish = self.leader.cancel();
+            self.finish_leader(finish);
+        }
+        self.activate(action);
+    }
+
+    fn press(&mut self, row: u8, col: u8, action: Action) {
+        self.pressed[row as usize][col as usize] = Some(action);
+        self.activate(action);
+    }
+
+    fn activate(&mut self, action: Action) {
+        match action {
+            Action::Key(key) => self.keys.insert(key),
+            Action::Consumer(usage) => self.set_consumer(Some(usage)),
+            Action::System(usage) => self.set_system(Some(usage)),
+            Action::Mouse(mouse) => {
+                if let Some(report) = self.mouse.press(mouse) {
+                    self.reports.push_back(Report::Mouse(report));
+                }
+            }
+            Action::MomentaryLayer(layer) => self.state.hold(layer),
+            Action::ToggleLayer(layer) => self.state.toggle(layer),
+            Action::OneShotLayer(layer) => self.state.oneshot_press(layer),
+            Action::DefaultLayer(layer) => self.state.set_default(layer),
+            Action::Host(host) => self.commands.push_back(Command::Host(host)),
+            Action::Transport(transport) => self.commands.push_back(Command::Transport(transport)),
+            Action::FactoryReset => self.reset = Some(self.now + FACTORY_RESET_HOLD),
+            Action::Macro(id) => self.player.start(id, self.now),
+            Action::RecordMacro(id) => self.record_macro(id),
+            Action::StopRecording => self.stop_recording(),
+            Action::TapHold(_)
+            | Action::TapDance(_)
+            | Action::Leader
+            | Action::Transparent
+            | Action::NoOp => {}
+        }
+
+        if !action.is_layer() {
+            self.state.oneshot_consume();
+        }
+        self.snapshot();
+    }
+
+    fn release(&mut self, action: Action) {
+        match action {
+            Action::Key(key) => self.keys.remove(key),
+            Action::Consumer(usage) if self.consumer == Some(usage) => self.set_consumer(None),
+            Action::System(usage) if self.system == Some(usage) => self.set_system(None),
+            Action::Mouse(mouse) => {
+                if let Some(report) = self.mouse.release(mouse) {
+                    self.reports.push_back(Report::Mouse(report));
+                }
+            }
+            Action::MomentaryLayer(layer) => self.state.release(layer),
+            Action::OneShotLayer(layer) => self.state.oneshot_release(layer),
+            Action::FactoryReset => self.reset = None,
+            Action::ToggleLayer(_)
+            | Action::DefaultLayer(_)
+            | Action::TapHold(_)
+            | Action::TapDance(_)
+            | Action::Host(_)
+            | Action::Transport(_)
+            | Action::Leader
+            | Action::Macro(_)
+            | Action::RecordMacro(_)
+            | Action::StopRecording
+            | Action::Consumer(_)
+            | Action::System(_)
+            | Action::Transparent
+            | Action::NoOp => {}
+        }
+        self.snapshot();
+    }
+
+    /// Queues the current keys as a report if they differ from the last queued one.
+    fn snapshot(&mut self) {
+        let mut keys = self.keys;
+        if let Some(text) = self.text {
+            keys = keys.without_modifiers();
+            text.iter().for_each(|key| keys.insert(key));
+        }
+
+        if keys != self.queued {
+            self.queued = keys;
+            self.reports.push_back(Report::Keyboard(keys));
+
+            let full = self
+                .recording
+                .as_mut()
+                .is_some_and(|recording| !recording.record(keys, self.now));
+            if full {
+                self.stop_recording();
+            }
+        }
+    }
+
+    /// Starts recording macro `id`, or stops the recording that is in progress.
+    fn record_macro(&mut self, id: u8) {
+        if self.recording.is_some() {
+            return self.stop_recording();
+        }
+        if !RECORDED_MACROS.contains(&id) {
+            return;
+        }
+        self.recording = Some(Recording::new(
+            id,
+            self.queued,
+            self.now,
+            self.player.interval(),
+        ));
+    }
+
+    fn stop_recording(&mut self) {
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+        let id = recording.id;
+        if self.macros.len() <= id as usize {
+            self.macros.resize(id as usize + 1, Macro::default());
+        }
+        self.macros[id as usize] = recording.finish();
+        self.commands.push_back(Command::SaveMacro(id));
+    }
+
+    /// Only the most recently pressed consumer key is reported.
+    fn set_consumer(&mut self, usage: Option<Consumer>) {
+        self.consumer = usage;
+        self.reports.push_back(Report::Consumer(usage));
+    }
+
+    fn set_system(&mut self, usage: Option<Desktop>) {
+        self.system = usage;
+        self.reports.push_back(Report::System(usage));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mouse::{MouseAction, MouseButton};
+    use super::*;
+
+    static LAYERS: [Layer<1, 1>; 1] = [[[Action::FactoryReset]]];
+
+    fn reset_commands(release_after: u64) -> usize {
+        let mut keymap = Keymap::new(&LAYERS);
+        let press = |pressed| KeyEvent {
+            row: 0,
+            col: 0,
+            pressed,
+        };
+
+        keymap.event(press(true), Instant::from_millis(0));
+        let released = Instant::from_millis(release_after);
+        while let Some(deadline) = keymap.next_deadline().filter(|d| *d <= released) {
+            keymap.tick(deadline);
+        }
+        keymap.event(press(false), released);
+        while let Some(deadline) = keymap.next_deadline() {
+            keymap.tick(deadline);
+        }
+
+        core::iter::from_fn(|| keymap.next_command())
+            .filter(|command| *command == Command::FactoryReset)
+            .count()
+    }
+
+    #[test]
+    fn latest_reports_keep_current_state() {
+        let mut keys = KeySet::default();
+        keys.insert(Keyboard::A);
+        let moved = MouseReport {
+            buttons: 1,
+            x: 5,
+            ..MouseReport::default()
+        };
+
+        let mut latest = LatestReports::new();
+        latest.push(Report::Keyboard(keys));
+        latest.push(Report::Consumer(Some(Consumer::PlayPause)));
+        latest.push(Report::Mouse(moved));
+        latest.push(Report::Consumer(None));
+        latest.push(Report::Keyboard(KeySet::default()));
+
+        let sent: Vec<Report> = latest.take().collect();
+        assert_eq!(
+            sent,
+            [
+                Report::Keyboard(KeySet::default()),
+                Report::Consumer(None),
+                Report::Mouse(MouseReport {
+                    buttons: 1,
+                    ..MouseReport::default()
+                }),
+            ]
+        );
+        assert_eq!(latest.take().count(), 0);
+    }
+
+    #[test]
+    fn key_set_subset() {
+        let mut a = KeySet::default();
+        a.insert(Keyboard::A);
+        let mut ab = a;
+        ab.insert(Keyboard::B);
+
+        assert!(KeySet::default().is_subset(&a));
+        assert!(a.is_subset(&ab));
+        assert!(!ab.is_subset(&a));
+    }
+
+    #[test]
+    fn records_only_dedicated_ids() {
+        static RECORD: [Layer<1, 3>; 1] = [[[
+            Action::RecordMacro(0),
+            Action::RecordMacro(RECORDED_MACROS.start),
+            Action::Key(Keyboard::A),
+        ]]];
+        let mut keymap = Keymap::new(&RECORD).with_macros(MacroConfig::default(), Vec::new());
+        let mut ms = 0;
+        let mut tap = |keymap: &mut Keymap<1, 3>, col| {
+            for pressed in [true, false] {
+                let event = KeyEvent {
+                    row: 0,
+                    col,
+                    pressed,
+                };
+                keymap.event(event, Instant::from_millis(ms));
+                ms += 100;
+            }
+        };
+
+        for col in [0, 2, 0] {
+            tap(&mut keymap, col);
+        }
+        assert_eq!(keymap.next_command(), None);
+
+        for col in [1, 2, 1] {
+            tap(&mut keymap, col);
+        }
+        let id = RECORDED_MACROS.start;
+        assert_eq!(keymap.next_command(), Some(Command::SaveMacro(id)));
+        assert_eq!(
+            keymap.get_macro(id).map(|recorded| recorded.steps.clone()),
+            Some(Vec::from([
+                MacroStep::press(Keyboard::A),
+                MacroStep::Delay(85),
+                MacroStep::release(Keyboard::A),
+            ]))
+        );
+    }
+
+    #[test]
+    fn recording_skips_other_than_keyboard_keys() {
+        static RECORD: [Layer<1, 3>; 1] = [[[
+            Action::RecordMacro(RECORDED_MACROS.start),
+            Action::Consumer(Consumer::PlayPause),
+            Action::Mouse(MouseAction::Button(MouseButton::Left)),
+        ]]];
+        let mut keymap = Keymap::new(&RECORD).with_macros(MacroConfig::default(), Vec::new());
+        let mut ms = 0;
+        for col in [0, 1, 2, 0] {
+            for pressed in [true, false] {
+                let event = KeyEvent {
+                    row: 0,
+                    col,
+                    pressed,
+                };
+                keymap.event(event, Instant::from_millis(ms));
+                ms += 100;
+            }
+        }
+
+        // They still reach the host while recording.
+        let reports: Vec<Report> = core::iter::from_fn(|| keymap.next_report()).collect();
+        assert!(reports.contains(&Report::Consumer(Some(Consumer::PlayPause))));
+        assert!(reports.contains(&Report::Consumer(None)));
+        assert!(reports
+            .iter()
+            .any(|report| matches!(report, Report::Mouse(mouse) if mouse.buttons != 0)));
+
+        let id = RECORDED_MACROS.start;
+        assert_eq!(keymap.next_command(), Some(Command::SaveMacro(id)));
+        assert_eq!(
+            keymap.get_macro(id).map(|recorded| recorded.steps.clone()),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn factory_reset_needs_hold() {
+        assert_eq!(reset_commands(100), 0);
+        assert_eq!(reset_commands(2900), 0);
+    }
+
+    #[test]
+    fn factory_reset_after_hold() {
+        assert_eq!(reset_commands(3000), 1);
+        assert_eq!(reset_commands(5000), 1);
+    }
+}
@@ -0,0 +1,351 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Pointer actions driven from the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MouseAction {
+    Button(MouseButton),
+    /// Moves the pointer, accelerating while held.
+    Move(Direction),
+    /// Scrolls by `scroll_step` every `scroll_interval` while held.
+    Wheel(Direction),
+}
+
+/// How the pointer speeds up from `initial_speed` to `max_speed` over `time_to_max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AccelCurve {
+    /// No acceleration, always moves at `max_speed`.
+    Constant,
+    Linear,
+    /// Slow start for precise movements, then catches up.
+    Quadratic,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MouseConfig {
+    /// Time between two movement reports while a movement key is held.
+    pub interval: Duration,
+    /// Pixels per report right after a movement key is pressed.
+    pub initial_speed: u8,
+    /// Pixels per report once fully accelerated, at most 127.
+    pub max_speed: u8,
+    pub time_to_max: Duration,
+    pub curve: AccelCurve,
+    /// Wheel steps per report.
+    pub scroll_step: u8,
+    /// Time between two wheel reports while a wheel key is held.
+    pub scroll_interval: Duration,
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl MouseConfig {
+    /// `Default::default` as a constant, so layouts can override single fields in a const.
+    pub const DEFAULT: Self = Self {
+        interval: Duration::from_millis(16),
+        initial_speed: 1,
+        max_speed: 20,
+        time_to_max: Duration::from_millis(1000),
+        curve: AccelCurve::Quadratic,
+        scroll_step: 1,
+        scroll_interval: Duration::from_millis(80),
+    };
+
+    /// Pixels per report after a movement key has been held for `held`.
+    pub fn speed(&self, held: Duration) -> u8 {
+        const ONE: u64 = 1024;
+
+        let max = self.max_speed.min(i8::MAX as u8);
+        let initial = self.initial_speed.min(max);
+        let time_to_max = self.time_to_max.as_millis();
+        if time_to_max == 0 {
+            return max;
+        }
+
+        let progress = held.as_millis().min(time_to_max) * ONE / time_to_max;
+        let eased = match self.curve {
+            AccelCurve::Constant => return max,
+            AccelCurve::Linear => progress,
+            AccelCurve::Quadratic => progress * progress / ONE,
+        };
+
+        initial + ((max - initial) as u64 * eased / ONE) as u8
+    }
+}
+
+/// Relative pointer movement and button state, sent as one mouse report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+/// Tracks held mouse keys and produces movement reports at the configured intervals.
+#[derive(Debug, Default)]
+pub(super) struct MouseKeys {
+    config: MouseConfig,
+    buttons: u8,
+    /// Held movement directions, one bit per `Direction`.
+    moving: u8,
+    /// Held wheel directions, one bit per `Direction`.
+    scrolling: u8,
+    /// When movement started, the acceleration is based on it.
+    started: Option<Instant>,
+    next_move: Option<Instant>,
+    next_scroll: Option<Instant>,
+}
+
+impl MouseKeys {
+    pub(super) fn new(config: MouseConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Applies a pressed mouse key. Button changes are reported right away, movement is
+    /// reported by the next `tick`.
+    pub(super) fn press(&mut self, action: MouseAction) -> Option<MouseReport> {
+        match action {
+            MouseAction::Button(button) => {
+                self.buttons |= 1 << button as u8;
+                return Some(self.report());
+            }
+            MouseAction::Move(direction) => {
+                if self.moving == 0 {
+                    self.started = None;
+                    self.next_move = Some(Instant::from_ticks(0));
+                }
+                self.moving |= direction.bit();
+            }
+            MouseAction::Wheel(direction) => {
+                if self.scrolling == 0 {
+                    self.next_scroll = Some(Instant::from_ticks(0));
+                }
+                self.scrolling |= direction.bit();
+            }
+        }
+        None
+    }
+
+    pub(super) fn release(&mut self, action: MouseAction) -> Option<MouseReport> {
+        match action {
+            MouseAction::Button(button) => {
+                self.buttons &= !(1 << button as u8);
+                return Some(self.report());
+            }
+            MouseAction::Move(direction) => {
+                self.moving &= !direction.bit();
+                if self.moving == 0 {
+                    self.started = None;
+                    self.next_move = None;
+                }
+            }
+            MouseAction::Wheel(direction) => {
+                self.scrolling &= !direction.bit();
+                if self.scrolling == 0 {
+                    self.next_scroll = None;
+                }
+            }
+        }
+        None
+    }
+
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        match (self.next_move, self.next_scroll) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Produces a movement report if a movement or wheel step is due at `now`.
+    pub(super) fn tick(&mut self, now: Instant) -> Option<MouseReport> {
+        let mut report = self.report();
+        let mut due = false;
+
+        if self.next_move.is_some_and(|next| next <= now) {
+            let started = *self.started.get_or_insert(now);
+            let speed = self.config.speed(now - started) as i8;
+            report.x = axis(self.moving, Direction::Right, Direction::Left) * speed;
+            report.y = axis(self.moving, Direction::Down, Direction::Up) * speed;
+            self.next_move = Some(now + self.config.interval);
+            due = true;
+        }
+
+        if self.next_scroll.is_some_and(|next| next <= now) {
+            let step = self.config.scroll_step.min(i8::MAX as u8) as i8;
+            report.wheel = axis(self.scrolling, Direction::Up, Direction::Down) * step;
+            report.pan = axis(self.scrolling, Direction::Right, Direction::Left) * step;
+            self.next_scroll = Some(now + self.config.scroll_interval);
+            due = true;
+        }
+
+        due.then_some(report)
+    }
+
+    fn report(&self) -> MouseReport {
+        MouseReport {
+            buttons: self.buttons,
+            ..Default::default()
+        }
+    }
+}
+
+/// 1 if only `positive` is held, -1 if only `negative` is held, 0 otherwise.
+fn axis(held: u8, positive: Direction, negative: Direction) -> i8 {
+    (held & positive.bit() != 0) as i8 - (held & negative.bit() != 0) as i8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn curve(curve: AccelCurve) -> MouseConfig {
+        MouseConfig {
+            curve,
+            ..MouseConfig::DEFAULT
+        }
+    }
+
+    #[test]
+    fn quadratic_speed() {
+        let config = curve(AccelCurve::Quadratic);
+        assert_eq!(config.speed(ms(0)), 1);
+        assert_eq!(config.speed(ms(500)), 5);
+        assert_eq!(config.speed(ms(1000)), 20);
+        assert_eq!(config.speed(ms(5000)), 20);
+    }
+
+    #[test]
+    fn linear_speed() {
+        let config = curve(AccelCurve::Linear);
+        assert_eq!(config.speed(ms(0)), 1);
+        assert_eq!(config.speed(ms(500)), 10);
+        assert_eq!(config.speed(ms(1000)), 20);
+    }
+
+    #[test]
+    fn constant_speed() {
+        let config = curve(AccelCurve::Constant);
+        assert_eq!(config.speed(ms(0)), 20);
+        assert_eq!(config.speed(ms(500)), 20);
+    }
+
+    #[test]
+    fn speed_limits() {
+        let config = MouseConfig {
+            initial_speed: 200,
+            max_speed: 200,
+            ..MouseConfig::DEFAULT
+        };
+        assert_eq!(config.speed(ms(0)), 127);
+
+        let config = MouseConfig {
+            time_to_max: ms(0),
+            ..MouseConfig::DEFAULT
+        };
+        assert_eq!(config.speed(ms(0)), 20);
+    }
+
+    #[test]
+    fn movement_accelerates_while_held() {
+        let mut mouse = MouseKeys::new(curve(AccelCurve::Linear));
+        assert_eq!(mouse.press(MouseAction::Move(Direction::Right)), None);
+
+        let x = |report: Option<MouseReport>| report.map(|report| report.x);
+        assert_eq!(x(mouse.tick(at(100))), Some(1));
+        assert_eq!(mouse.next_deadline(), Some(at(116)));
+        assert_eq!(x(mouse.tick(at(110))), None);
+        assert_eq!(x(mouse.tick(at(600))), Some(10));
+        assert_eq!(x(mouse.tick(at(1100))), Some(20));
+
+        assert_eq!(mouse.release(MouseAction::Move(Direction::Right)), None);
+        assert_eq!(mouse.next_deadline(), None);
+
+        // Starts slow again after a release.
+        mouse.press(MouseAction::Move(Direction::Right));
+        assert_eq!(x(mouse.tick(at(1200))), Some(1));
+    }
+
+    #[test]
+    fn diagonal_and_opposite_directions() {
+        let mut mouse = MouseKeys::default();
+        mouse.press(MouseAction::Move(Direction::Down));
+        mouse.press(MouseAction::Move(Direction::Left));
+        let report = mouse.tick(at(0)).unwrap();
+        assert_eq!((report.x, report.y), (-1, 1));
+
+        mouse.press(MouseAction::Move(Direction::Right));
+        let report = mouse.tick(at(16)).unwrap();
+        assert_eq!((report.x, report.y), (0, 1));
+    }
+
+    #[test]
+    fn wheel_steps_at_scroll_interval() {
+        let mut mouse = MouseKeys::default();
+        mouse.press(MouseAction::Wheel(Direction::Up));
+
+        assert_eq!(mouse.tick(at(0)).map(|report| report.wheel), Some(1));
+        assert_eq!(mouse.tick(at(50)), None);
+        assert_eq!(mouse.tick(at(80)).map(|report| report.wheel), Some(1));
+
+        mouse.release(MouseAction::Wheel(Direction::Up));
+        mouse.press(MouseAction::Wheel(Direction::Left));
+        assert_eq!(mouse.tick(at(100)).map(|report| report.pan), Some(-1));
+    }
+
+    #[test]
+    fn buttons_are_reported_right_away() {
+        let mut mouse = MouseKeys::default();
+
+        let report = mouse.press(MouseAction::Button(MouseButton::Left));
+        assert_eq!(report.map(|report| report.buttons), Some(0b001));
+        let report = mouse.press(MouseAction::Button(MouseButton::Middle));
+        assert_eq!(report.map(|report| report.buttons), Some(0b101));
+        let report = mouse.release(MouseAction::Button(MouseButton::Left));
+        assert_eq!(report.map(|report| report.buttons), Some(0b100));
+
+        // Held buttons are part of movement reports.
+        mouse.press(MouseAction::Move(Direction::Up));
+        assert_eq!(mouse.tick(at(0)).map(|report| report.buttons), Some(0b100));
+        assert_eq!(mouse.next_deadline(), Some(at(16)));
+    }
+}
@@ -0,0 +1,209 @@
+use super::action::Action;
+use embassy_time::{Duration, Instant};
+
+/// A key that does something different depending on how often it is tapped in a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDance {
+    /// What one, two, ... taps do. Once the last entry is reached the dance ends right away.
+    pub taps: &'static [Action],
+    /// What holding the key on the first, second, ... tap does. Without an entry the tap
+    /// action is held instead.
+    pub holds: &'static [Action],
+}
+
+impl TapDance {
+    /// The action for `count` taps, the last one for higher counts.
+    pub fn tap(&self, count: u8) -> Action {
+        let idx = (count as usize).saturating_sub(1);
+        self.taps
+            .get(idx)
+            .or(self.taps.last())
+            .copied()
+            .unwrap_or(Action::NoOp)
+    }
+
+    /// The action for holding the key on tap `count`.
+    pub fn hold(&self, count: u8) -> Action {
+        let idx = (count as usize).saturating_sub(1);
+        self.holds
+            .get(idx)
+            .copied()
+            .unwrap_or_else(|| self.tap(count))
+    }
+
+    /// True if another tap can't change the outcome anymore.
+    fn is_last(&self, count: u8) -> bool {
+        count as usize >= self.taps.len().max(self.holds.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDanceConfig {
+    /// Time after a press or release in which the next tap continues the dance.
+    pub term: Duration,
+}
+
+impl Default for TapDanceConfig {
+    fn default() -> Self {
+        Self {
+            term: Duration::from_millis(200),
+        }
+    }
+}
+
+/// A tap dance that is still counting taps.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Dance {
+    pub row: u8,
+    pub col: u8,
+    pub tap_dance: TapDance,
+    pub count: u8,
+    /// The key is down right now.
+    pub held: bool,
+    pub deadline: Instant,
+}
+
+/// How a dance ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Outcome {
+    /// Send the action as a tap, the key is already released.
+    Tap(Action),
+    /// Press the action until the key is released.
+    Hold(Action),
+}
+
+impl Dance {
+    pub(super) fn new(row: u8, col: u8, tap_dance: TapDance, now: Instant, term: Duration) -> Self {
+        Self {
+            row,
+            col,
+            tap_dance,
+            count: 1,
+            held: true,
+            deadline: now + term,
+        }
+    }
+
+    /// Applies a press or release of the dance key, returns the outcome if the dance ended.
+    pub(super) fn event(&mut self, pressed: bool, now: Instant, term: Duration) -> Option<Outcome> {
+        self.held = pressed;
+        self.deadline = now + term;
+
+        if pressed {
+            self.count = self.count.saturating_add(1);
+            None
+        } else if self.tap_dance.is_last(self.count) {
+            Some(Outcome::Tap(self.tap_dance.tap(self.count)))
+        } else {
+            None
+        }
+    }
+
+    /// The outcome once the term passed without another tap.
+    pub(super) fn timeout(&self) -> Outcome {
+        if self.held {
+            Outcome::Hold(self.tap_dance.hold(self.count))
+        } else {
+            Outcome::Tap(self.tap_dance.tap(self.count))
+        }
+    }
+
+    /// The outcome when another key is pressed during the dance. A held key acts as its hold
+    /// action, so e.g. its layer applies to the other key.
+    pub(super) fn interrupt(&self) -> Outcome {
+        if self.held {
+            Outcome::Hold(self.tap_dance.hold(self.count))
+        } else {
+            Outcome::Tap(self.tap_dance.tap(self.count))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Keymap, Layer, Report};
+    use super::*;
+    use crate::matrix::KeyEvent;
+    use alloc::{vec, vec::Vec};
+    use usbd_human_interface_device::page::Keyboard::{self, *};
+
+    const ESC_CAPS: Action = Action::TapDance(TapDance {
+        taps: &[Action::Key(Escape), Action::Key(CapsLock)],
+        holds: &[Action::MomentaryLayer(1)],
+    });
+
+    static LAYERS: [Layer<1, 2>; 2] = [
+        [[ESC_CAPS, Action::Key(Q)]],
+        [[Action::Transparent, Action::Key(Keyboard1)]],
+    ];
+
+    /// Replays `(ms, col, pressed)` events and returns the keyboard reports.
+    fn run(events: &[(u64, u8, bool)]) -> Vec<Vec<Keyboard>> {
+        let mut keymap = Keymap::new(&LAYERS).with_tap_dance(TapDanceConfig::default());
+        for &(ms, col, pressed) in events {
+            let now = Instant::from_millis(ms);
+            while let Some(deadline) = keymap.next_deadline().filter(|d| *d <= now) {
+                keymap.tick(deadline);
+            }
+            keymap.event(
+                KeyEvent {
+                    row: 0,
+                    col,
+                    pressed,
+                },
+                now,
+            );
+        }
+        while let Some(deadline) = keymap.next_deadline() {
+            keymap.tick(deadline);
+        }
+
+        let mut reports = Vec::new();
+        while let Some(report) = keymap.next_report() {
+            if let Report::Keyboard(keys) = report {
+                reports.push(keys.iter().collect());
+            }
+        }
+        reports
+    }
+
+    #[test]
+    fn single_tap() {
+        let reports = run(&[(0, 0, true), (50, 0, false)]);
+        assert_eq!(reports, [vec![Escape], vec![]]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let events = [
+            (0, 0, true),
+            (50, 0, false),
+            (100, 0, true),
+            (150, 0, false),
+        ];
+        assert_eq!(run(&events), [vec![CapsLock], vec![]]);
+    }
+
+    #[test]
+    fn hold_after_term() {
+        let events = [
+            (0, 0, true),
+            (300, 1, true),
+            (310, 1, false),
+            (400, 0, false),
+        ];
+        assert_eq!(run(&events), [vec![Keyboard1], vec![]]);
+    }
+
+    #[test]
+    fn interrupt_while_held_uses_hold_action() {
+        let events = [(0, 0, true), (50, 1, true), (60, 1, false), (70, 0, false)];
+        assert_eq!(run(&events), [vec![Keyboard1], vec![]]);
+    }
+
+    #[test]
+    fn interrupt_after_release_taps() {
+        let events = [(0, 0, true), (50, 0, false), (60, 1, true), (70, 1, false)];
+        assert_eq!(run(&events), [vec![Escape], vec![], vec![Q], vec![]]);
+    }
+}
@@ -0,0 +1,251 @@
+use super::action::Action;
+use embassy_time::{Duration, Instant};
+use usbd_human_interface_device::page::Keyboard;
+
+/// A key that sends `tap` when tapped and activates `hold` when held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapHold {
+    pub tap: Keyboard,
+    pub hold: Hold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hold {
+    /// Holds a modifier key, e.g. `Keyboard::LeftShift` for a home-row shift.
+    Modifier(Keyboard),
+    /// Activates a layer like `Action::MomentaryLayer`.
+    Layer(u8),
+}
+
+impl Hold {
+    pub fn action(&self) -> Action {
+        match *self {
+            Hold::Modifier(key) => Action::Key(key),
+            Hold::Layer(layer) => Action::MomentaryLayer(layer),
+        }
+    }
+}
+
+/// Decides how an undecided tap-hold key resolves when other keys are used during the
+/// tapping term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapHoldConfig {
+    /// A key held for longer than this resolves to its hold action.
+    pub tapping_term: Duration,
+    /// Resolve to hold when another key is pressed and released while the tap-hold key is down.
+    pub permissive_hold: bool,
+    /// Resolve to hold as soon as another key is pressed while the tap-hold key is down.
+    pub hold_on_other_key_press: bool,
+    /// Send the tap action when a key held past the tapping term is released without any
+    /// other key being pressed in between.
+    pub retro_tapping: bool,
+}
+
+impl Default for TapHoldConfig {
+    fn default() -> Self {
+        Self {
+            tapping_term: Duration::from_millis(200),
+            permissive_hold: false,
+            hold_on_other_key_press: false,
+            retro_tapping: false,
+        }
+    }
+}
+
+/// A tap-hold key that has been pressed but not yet resolved.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Pending {
+    pub row: u8,
+    pub col: u8,
+    pub tap_hold: TapHold,
+    pub deadline: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Resolution {
+    Tap,
+    Hold,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Keymap, Layer, Report};
+    use super::*;
+    use crate::matrix::KeyEvent;
+    use alloc::{vec, vec::Vec};
+    use usbd_human_interface_device::page::Keyboard::*;
+
+    const HOME_F: Action = Action::TapHold(TapHold {
+        tap: F,
+        hold: Hold::Modifier(LeftShift),
+    });
+    const LAYER_J: Action = Action::TapHold(TapHold {
+        tap: J,
+        hold: Hold::Layer(1),
+    });
+
+    static LAYERS: [Layer<1, 3>; 2] = [
+        [[HOME_F, Action::Key(Q), LAYER_J]],
+        [[
+            Action::Transparent,
+            Action::Key(Keyboard1),
+            Action::Transparent,
+        ]],
+    ];
+
+    fn config(permissive_hold: bool, hold_on_other_key_press: bool, retro: bool) -> TapHoldConfig {
+        TapHoldConfig {
+            tapping_term: Duration::from_millis(200),
+            permissive_hold,
+            hold_on_other_key_press,
+            retro_tapping: retro,
+        }
+    }
+
+    /// Replays `(ms, col, pressed)` events and returns the keyboard reports.
+    fn run(config: TapHoldConfig, events: &[(u64, u8, bool)]) -> Vec<Vec<Keyboard>> {
+        let mut keymap = Keymap::with_tap_hold(&LAYERS, config);
+        for &(ms, col, pressed) in events {
+            let now = Instant::from_millis(ms);
+            while let Some(deadline) = keymap.next_deadline().filter(|d| *d <= now) {
+                keymap.tick(deadline);
+            }
+            keymap.event(
+                KeyEvent {
+                    row: 0,
+                    col,
+                    pressed,
+                },
+                now,
+            );
+        }
+        while let Some(deadline) = keymap.next_deadline() {
+            keymap.tick(deadline);
+        }
+
+        let mut reports = Vec::new();
+        while let Some(report) = keymap.next_report() {
+            if let Report::Keyboard(keys) = report {
+                reports.push(keys.iter().collect());
+            }
+        }
+        reports
+    }
+
+    #[test]
+    fn tap() {
+        let reports = run(config(false, false, false), &[(0, 0, true), (50, 0, false)]);
+        assert_eq!(reports, [vec![F], vec![]]);
+    }
+
+    #[test]
+    fn hold_after_term() {
+        let events = [
+            (0, 0, true),
+            (300, 1, true),
+            (310, 1, false),
+            (400, 0, false),
+        ];
+        let reports = run(config(false, false, false), &events);
+        assert_eq!(
+            reports,
+            [vec![LeftShift], vec![Q, LeftShift], vec![LeftShift], vec![]]
+        );
+    }
+
+    #[test]
+    fn rolled_key_is_tap_by_default() {
+        let events = [
+            (0, 0, true),
+            (50, 1, true),
+            (100, 1, false),
+            (150, 0, false),
+        ];
+        let reports = run(config(false, false, false), &events);
+        assert_eq!(reports, [vec![F], vec![F, Q], vec![F], vec![]]);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let events = [
+            (0, 0, true),
+            (50, 1, true),
+            (100, 1, false),
+            (150, 0, false),
+        ];
+        let reports = run(config(true, false, false), &events);
+        assert_eq!(
+            reports,
+            [vec![LeftShift], vec![Q, LeftShift], vec![LeftShift], vec![]]
+        );
+    }
+
+    #[test]
+    fn permissive_hold_roll_is_tap() {
+        let events = [
+            (0, 0, true),
+            (50, 1, true),
+            (100, 0, false),
+            (150, 1, false),
+        ];
+        let reports = run(config(true, false, false), &events);
+        assert_eq!(reports, [vec![F], vec![F, Q], vec![Q], vec![]]);
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let events = [
+            (0, 0, true),
+            (50, 1, true),
+            (100, 0, false),
+            (150, 1, false),
+        ];
+        let reports = run(config(false, true, false), &events);
+        assert_eq!(
+            reports,
+            [vec![LeftShift], vec![Q, LeftShift], vec![Q], vec![]]
+        );
+    }
+
+    #[test]
+    fn layer_hold() {
+        let events = [
+            (0, 2, true),
+            (300, 1, true),
+            (310, 1, false),
+            (400, 2, false),
+        ];
+        let reports = run(config(false, false, false), &events);
+        assert_eq!(reports, [vec![Keyboard1], vec![]]);
+    }
+
+    #[test]
+    fn retro_tapping_off() {
+        let reports = run(
+            config(false, false, false),
+            &[(0, 0, true), (500, 0, false)],
+        );
+        assert_eq!(reports, [vec![LeftShift], vec![]]);
+    }
+
+    #[test]
+    fn retro_tapping_on() {
+        let reports = run(config(false, false, true), &[(0, 0, true), (500, 0, false)]);
+        assert_eq!(reports, [vec![LeftShift], vec![], vec![F], vec![]]);
+    }
+
+    #[test]
+    fn retro_tapping_not_after_other_key() {
+        let events = [
+            (0, 0, true),
+            (300, 1, true),
+            (310, 1, false),
+            (500, 0, false),
+        ];
+        let reports = run(config(false, false, true), &events);
+        assert_eq!(
+            reports,
+            [vec![LeftShift], vec![Q, LeftShift], vec![LeftShift], vec![]]
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use usbd_human_interface_device::page::Keyboard::{self, *};
+
+/// The key and whether Shift is needed to type `c` on a US layout host, None for characters
+/// that can't be typed.
+pub fn ascii_key(c: char) -> Option<(Keyboard, bool)> {
+    const LETTERS: [Keyboard; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [Keyboard; 10] = [
+        Keyboard0, Keyboard1, Keyboard2, Keyboard3, Keyboard4, Keyboard5, Keyboard6, Keyboard7,
+        Keyboard8, Keyboard9,
+    ];
+
+    let key = match c {
+        'a'..='z' => (LETTERS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTERS[c as usize - 'A' as usize], true),
+        '0'..='9' => (DIGITS[c as usize - '0' as usize], false),
+        '!' => (Keyboard1, true),
+        '@' => (Keyboard2, true),
+        '#' => (Keyboard3, true),
+        '$' => (Keyboard4, true),
+        '%' => (Keyboard5, true),
+        '^' => (Keyboard6, true),
+        '&' => (Keyboard7, true),
+        '*' => (Keyboard8, true),
+        '(' => (Keyboard9, true),
+        ')' => (Keyboard0, true),
+        '\n' => (ReturnEnter, false),
+        '\t' => (Tab, false),
+        ' ' => (Space, false),
+        '-' => (Minus, false),
+        '_' => (Minus, true),
+        '=' => (Equal, false),
+        '+' => (Equal, true),
+        '[' => (LeftBrace, false),
+        '{' => (LeftBrace, true),
+        ']' => (RightBrace, false),
+        '}' => (RightBrace, true),
+        '\\' => (Backslash, false),
+        '|' => (Backslash, true),
+        ';' => (Semicolon, false),
+        ':' => (Semicolon, true),
+        '\'' => (Apostrophe, false),
+        '"' => (Apostrophe, true),
+        '`' => (Grave, false),
+        '~' => (Grave, true),
+        ',' => (Comma, false),
+        '<' => (Comma, true),
+        '.' => (Dot, false),
+        '>' => (Dot, true),
+        '/' => (ForwardSlash, false),
+        '?' => (ForwardSlash, true),
+        _ => return None,
+    };
+    Some(key)
+}
@@ -0,0 +1,375 @@
+use defmt::{error, info, warn, Format};
+use ekv::flash::Flash;
+use ekv::WriteTransaction;
+use ekv::{CommitError, Database, MountError, ReadError, WriteError};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, Format)]
+pub enum DBReadError<E> {
+    IO(ReadError<E>),
+    Deserialize(postcard::Error),
+}
+impl<E> From<postcard::Error> for DBReadError<E> {
+    fn from(value: postcard::Error) -> Self {
+        DBReadError::Deserialize(value)
+    }
+}
+impl<E> From<ReadError<E>> for DBReadError<E> {
+    fn from(value: ReadError<E>) -> Self {
+        DBReadError::IO(value)
+    }
+}
+#[derive(Debug, Format)]
+pub enum DBWriteError<E> {
+    WriteError(WriteError<E>),
+    CommitError(CommitError<E>),
+    SerializeError(postcard::Error),
+}
+
+impl<E> From<WriteError<E>> for DBWriteError<E> {
+    fn from(value: WriteError<E>) -> Self {
+        DBWriteError::WriteError(value)
+    }
+}
+
+impl<E> From<CommitError<E>> for DBWriteError<E> {
+    fn from(value: CommitError<E>) -> Self {
+        DBWriteError::CommitError(value)
+    }
+}
+impl<E> From<postcard::Error> for DBWriteError<E> {
+    fn from(value: postcard::Error) -> Self {
+        Self::SerializeError(value)
+    }
+}
+
+pub trait SerdeDB {
+    type Flash: Flash;
+    type ReadError;
+    type WriteError;
+    async fn read<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<T, Self::ReadError>;
+
+    async fn write<T: Serialize>(
+        &self,
+        key: impl AsRef<[u8]>,
+        val: &T,
+        wtx: &mut WriteTransaction<'_, Self::Flash, NoopRawMutex>,
+    ) -> Result<(), Self::WriteError>;
+}
+
+/// Generic over the flash so the store can be tested against memory.
+impl<F: Flash> SerdeDB for Database<F, NoopRawMutex> {
+    type Flash = F;
+    type ReadError = DBReadError<F::Error>;
+    type WriteError = DBWriteError<F::Error>;
+
+    async fn read<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<T, Self::ReadError> {
+        let mut rtx = self.read_transaction().await;
+        let mut buf = [0u8; ekv::config::MAX_VALUE_SIZE];
+        let r_len = rtx.read(key.as_ref(), &mut buf).await?;
+        let data = &buf[..r_len];
+
+        let data = postcard::from_bytes::<T>(data)?;
+        Ok(data)
+    }
+
+    async fn write<T: Serialize>(
+        &self,
+        key: impl AsRef<[u8]>,
+        val: &T,
+        wtx: &mut WriteTransaction<'_, F, NoopRawMutex>,
+    ) -> Result<(), Self::WriteError> {
+        let mut buf = [0u8; ekv::config::MAX_VALUE_SIZE];
+        let buf = postcard::to_slice(val, &mut buf)?;
+        wtx.write(key.as_ref(), buf).await?;
+
+        Ok(())
+    }
+}
+
+pub trait DBKey {
+    fn key(&self) -> &[u8];
+}
+
+/// Writes a single value in its own transaction.
+pub async fn store<F: Flash, T: Serialize>(
+    db: &Database<F, NoopRawMutex>,
+    key: &[u8],
+    val: &T,
+) -> Result<(), DBWriteError<F::Error>> {
+    let mut wtx = db.write_transaction().await;
+    db.write(key, val, &mut wtx).await?;
+    wtx.commit().await?;
+    Ok(())
+}
+
+/// Why the store was last formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+pub enum FormatReason {
+    /// The store could not be mounted, this is also the case on first boot.
+    Corrupted,
+    /// Formatted on request, see `FACTORY_RESET`.
+    FactoryReset,
+}
+
+/// Kept under `FormatRecord::KEY`, which no other data may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+pub struct FormatRecord {
+    /// Number of formats. It survives factory resets, but restarts when the store was corrupted
+    /// since the previous record is lost then.
+    pub count: u32,
+    pub reason: FormatReason,
+}
+
+impl FormatRecord {
+    pub const KEY: &'static [u8] = b"_format";
+}
+
+/// Formats the store and records the reason, wiping everything else. `count` is the number
+/// of earlier formats.
+async fn format<F: Flash>(db: &Database<F, NoopRawMutex>, reason: FormatReason, count: u32)
+where
+    F::Error: Format,
+{
+    db.format().await.expect("Failed to format DB");
+
+    let record = FormatRecord {
+        count: count.saturating_add(1),
+        reason,
+    };
+    info!("Formatted DB: {}", record);
+    if let Err(e) = store(db, FormatRecord::KEY, &record).await {
+        error!("Failed to store format record: {}", e);
+    }
+}
+
+/// Mounts the store, formatting it if it is corrupted or was never formatted.
+pub async fn mount<F: Flash>(db: &Database<F, NoopRawMutex>) -> Result<(), F::Error>
+where
+    F::Error: Format,
+{
+    match db.mount().await {
+        Ok(()) => match db.read::<FormatRecord>(FormatRecord::KEY).await {
+            Ok(record) => info!("Mounted DB, last format: {}", record),
+            Err(_) => info!("Mounted DB"),
+        },
+        Err(MountError::Corrupted) => {
+            warn!("DB corrupted or not formatted yet");
+            format(db, FormatReason::Corrupted, 0).await;
+        }
+        Err(MountError::Io(e)) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Formats the store, counting the format on top of the earlier ones.
+pub async fn factory_reset<F: Flash>(db: &Database<F, NoopRawMutex>)
+where
+    F::Error: Format,
+{
+    let count = match db.read::<FormatRecord>(FormatRecord::KEY).await {
+        Ok(record) => record.count,
+        Err(_) => 0,
+    };
+    format(db, FormatReason::FactoryReset, count).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use ekv::config::PAGE_SIZE;
+    use ekv::flash::PageID;
+
+    const PAGES: usize = 32;
+
+    /// The power was cut while writing.
+    #[derive(Debug, Format)]
+    struct PowerLoss;
+
+    /// Flash in memory that outlives the database, so it can be mounted again as after a
+    /// restart.
+    struct MemFlash {
+        data: Rc<RefCell<Vec<u8>>>,
+        /// Writes that still succeed before the power is cut, None for no limit. The write
+        /// that cuts the power only stores the first half of its data.
+        writes_left: Option<usize>,
+    }
+
+    impl MemFlash {
+        fn new() -> Self {
+            Self {
+                data: Rc::new(RefCell::new(vec![0xFF; PAGES * PAGE_SIZE])),
+                writes_left: None,
+            }
+        }
+
+        /// The same flash after a restart.
+        fn restart(&self) -> Self {
+            Self {
+                data: self.data.clone(),
+                writes_left: None,
+            }
+        }
+    }
+
+    impl Flash for MemFlash {
+        type Error = PowerLoss;
+
+        fn page_count(&self) -> usize {
+            PAGES
+        }
+
+        async fn erase(&mut self, page_id: PageID) -> Result<(), PowerLoss> {
+            if self.writes_left == Some(0) {
+                return Err(PowerLoss);
+            }
+            let start = page_id.index() * PAGE_SIZE;
+            self.data.borrow_mut()[start..start + PAGE_SIZE].fill(0xFF);
+            Ok(())
+        }
+
+        async fn read(
+            &mut self,
+            page_id: PageID,
+            offset: usize,
+            data: &mut [u8],
+        ) -> Result<(), PowerLoss> {
+            let start = page_id.index() * PAGE_SIZE + offset;
+            data.copy_from_slice(&self.data.borrow()[start..start + data.len()]);
+            Ok(())
+        }
+
+        async fn write(
+            &mut self,
+            page_id: PageID,
+            offset: usize,
+            data: &[u8],
+        ) -> Result<(), PowerLoss> {
+            let start = page_id.index() * PAGE_SIZE + offset;
+            let len = match self.writes_left {
+                Some(0) => data.len() / 2,
+                _ => data.len(),
+            };
+            self.data.borrow_mut()[start..start + len].copy_from_slice(&data[..len]);
+
+            match self.writes_left.as_mut() {
+                Some(0) => Err(PowerLoss),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Runs a future that never has to wait, which holds for a store in memory.
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        fn raw_waker() -> RawWaker {
+            const VTABLE: RawWakerVTable =
+                RawWakerVTable::new(|_| raw_waker(), |_| {}, |_| {}, |_| {});
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn mounted(flash: MemFlash) -> Database<MemFlash, NoopRawMutex> {
+        let db = Database::new(flash, ekv::Config::default());
+        block_on(mount(&db)).unwrap();
+        db
+    }
+
+    fn format_record(db: &Database<MemFlash, NoopRawMutex>) -> FormatRecord {
+        block_on(db.read(FormatRecord::KEY)).unwrap()
+    }
+
+    const KEY: &[u8] = b"value";
+
+    #[test]
+    fn blank_flash_is_formatted() {
+        let db = mounted(MemFlash::new());
+
+        let record = format_record(&db);
+        assert_eq!(record.count, 1);
+        assert_eq!(record.reason, FormatReason::Corrupted);
+    }
+
+    #[test]
+    fn mounts_after_power_loss_mid_write() {
+        let flash = MemFlash::new();
+        let db = mounted(flash.restart());
+        let mut value = 1u32;
+        block_on(store(&db, KEY, &value)).unwrap();
+
+        // Cut the power at every write of a store in turn.
+        for writes in 0..16 {
+            let db = mounted(MemFlash {
+                data: flash.data.clone(),
+                writes_left: Some(writes),
+            });
+            if block_on(store(&db, KEY, &(value + 1))).is_ok() {
+                value += 1;
+            }
+
+            // An interrupted store is lost, but nothing that was committed before it.
+            let db = mounted(flash.restart());
+            assert_eq!(block_on(db.read::<u32>(KEY)).unwrap(), value);
+            assert_eq!(format_record(&db).count, 1);
+        }
+    }
+
+    #[test]
+    fn corrupted_flash_is_formatted() {
+        let flash = MemFlash::new();
+        let db = mounted(flash.restart());
+        block_on(store(&db, KEY, &1u32)).unwrap();
+        block_on(factory_reset(&db));
+        assert_eq!(format_record(&db).count, 2);
+
+        flash.data.borrow_mut().fill(0x00);
+        let db = mounted(flash.restart());
+
+        // The earlier record is gone with everything else, so counting starts over.
+        let record = format_record(&db);
+        assert_eq!(record.count, 1);
+        assert_eq!(record.reason, FormatReason::Corrupted);
+        assert!(matches!(
+            block_on(db.read::<u32>(KEY)),
+            Err(DBReadError::IO(ReadError::KeyNotFound))
+        ));
+    }
+
+    #[test]
+    fn factory_reset_counts_formats() {
+        let flash = MemFlash::new();
+        let db = mounted(flash.restart());
+        block_on(store(&db, KEY, &1u32)).unwrap();
+
+        block_on(factory_reset(&db));
+        block_on(factory_reset(&db));
+
+        let db = mounted(flash.restart());
+        let record = format_record(&db);
+        assert_eq!(record.count, 3);
+        assert_eq!(record.reason, FormatReason::FactoryReset);
+        assert!(matches!(
+            block_on(db.read::<u32>(KEY)),
+            Err(DBReadError::IO(ReadError::KeyNotFound))
+        ));
+    }
+}
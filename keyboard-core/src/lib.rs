@@ -0,0 +1,36 @@
+//! Keyboard logic that doesn't touch the hardware, so it can be tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod advertising;
+pub mod battery;
+pub mod bond;
+pub mod debouncer;
+pub mod hid;
+pub mod keymap;
+pub mod kvstore;
+pub mod matrix;
+pub mod passkey;
+
+/// Host tests have no RTT, defmt output is dropped.
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+}
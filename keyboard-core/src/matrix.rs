@@ -0,0 +1,230 @@
+use crate::debouncer::Debouncer;
+use defmt::Format;
+
+/// Number of consecutive samples before a debounced key reports a change.
+pub const DEBOUNCE_TRANSIENT: i8 = 2;
+/// Number of consecutive samples before a debounced key is considered settled.
+pub const DEBOUNCE_STEADY: i8 = 5;
+
+/// A line of the matrix that is driven while its row or column is being scanned.
+pub trait MatrixOutput {
+    fn select(&mut self);
+    fn unselect(&mut self);
+}
+
+/// A line of the matrix that is sampled while an output is selected.
+pub trait MatrixInput {
+    fn is_active(&self) -> bool;
+}
+
+/// Which way the switch diodes point, which decides whether rows or columns are driven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DiodeDirection {
+    /// Rows are driven and columns are read.
+    Col2Row,
+    /// Columns are driven and rows are read.
+    Row2Col,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+}
+
+impl DiodeDirection {
+    fn event(&self, output: usize, input: usize, pressed: bool) -> KeyEvent {
+        let (row, col) = match self {
+            DiodeDirection::Col2Row => (output, input),
+            DiodeDirection::Row2Col => (input, output),
+        };
+
+        KeyEvent {
+            row: row as u8,
+            col: col as u8,
+            pressed,
+        }
+    }
+}
+
+/// Hardware agnostic key matrix scanner.
+///
+/// Every intersection of an output and an input has its own debouncer, and an event is only
+/// emitted once the debounced state of that intersection changes.
+pub struct Matrix<I, O, const INPUTS: usize, const OUTPUTS: usize> {
+    inputs: [I; INPUTS],
+    outputs: [O; OUTPUTS],
+    direction: DiodeDirection,
+    debouncers: [[Debouncer; INPUTS]; OUTPUTS],
+}
+
+impl<I, O, const INPUTS: usize, const OUTPUTS: usize> Matrix<I, O, INPUTS, OUTPUTS>
+where
+    I: MatrixInput,
+    O: MatrixOutput,
+{
+    pub fn new(inputs: [I; INPUTS], mut outputs: [O; OUTPUTS], direction: DiodeDirection) -> Self {
+        outputs.iter_mut().for_each(|output| output.unselect());
+
+        let debouncers = core::array::from_fn(|_| {
+            core::array::from_fn(|_| Debouncer::new(false, DEBOUNCE_TRANSIENT, DEBOUNCE_STEADY))
+        });
+
+        Self {
+            inputs,
+            outputs,
+            direction,
+            debouncers,
+        }
+    }
+
+    pub fn direction(&self) -> DiodeDirection {
+        self.direction
+    }
+
+    /// Scans every intersection once and calls `emit` for each debounced press or release.
+    pub fn scan(&mut self, mut emit: impl FnMut(KeyEvent)) {
+        for (o, output) in self.outputs.iter_mut().enumerate() {
+            output.select();
+
+            for (i, input) in self.inputs.iter().enumerate() {
+                let debouncer = &mut self.debouncers[o][i];
+                if debouncer.update(input.is_active()) {
+                    emit(self.direction.event(o, i, debouncer.output()));
+                }
+            }
+
+            output.unselect();
+        }
+    }
+
+    /// Returns true if any key is currently held after debouncing.
+    pub fn any_pressed(&self) -> bool {
+        self.debouncers
+            .iter()
+            .flatten()
+            .any(|debouncer| debouncer.output())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    /// Switches closed by `(output, input)` and the output being driven.
+    #[derive(Default)]
+    struct Board {
+        closed: Vec<(usize, usize)>,
+        selected: Option<usize>,
+    }
+
+    struct Output(usize, Rc<RefCell<Board>>);
+
+    impl MatrixOutput for Output {
+        fn select(&mut self) {
+            let mut board = self.1.borrow_mut();
+            assert_eq!(board.selected, None, "two outputs selected");
+            board.selected = Some(self.0);
+        }
+
+        fn unselect(&mut self) {
+            let mut board = self.1.borrow_mut();
+            if board.selected == Some(self.0) {
+                board.selected = None;
+            }
+        }
+    }
+
+    struct Input(usize, Rc<RefCell<Board>>);
+
+    impl MatrixInput for Input {
+        fn is_active(&self) -> bool {
+            let board = self.1.borrow();
+            let selected = board
+                .selected
+                .expect("input read without a selected output");
+            board.closed.contains(&(selected, self.0))
+        }
+    }
+
+    fn matrix(direction: DiodeDirection) -> (Matrix<Input, Output, 3, 2>, Rc<RefCell<Board>>) {
+        let board = Rc::new(RefCell::new(Board::default()));
+        let inputs = core::array::from_fn(|i| Input(i, board.clone()));
+        let outputs = core::array::from_fn(|o| Output(o, board.clone()));
+        (Matrix::new(inputs, outputs, direction), board)
+    }
+
+    /// Scans `count` times and returns the events by scan.
+    fn scan(matrix: &mut Matrix<Input, Output, 3, 2>, count: usize) -> Vec<(usize, KeyEvent)> {
+        let mut events = Vec::new();
+        for n in 0..count {
+            matrix.scan(|event| events.push((n, event)));
+        }
+        events
+    }
+
+    fn event(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { row, col, pressed }
+    }
+
+    #[test]
+    fn press_and_release_are_debounced() {
+        let (mut matrix, board) = matrix(DiodeDirection::Col2Row);
+
+        board.borrow_mut().closed.push((1, 2));
+        assert_eq!(scan(&mut matrix, 10), [(1, event(1, 2, true))]);
+        assert!(matrix.any_pressed());
+
+        board.borrow_mut().closed.clear();
+        assert_eq!(scan(&mut matrix, 10), [(1, event(1, 2, false))]);
+        assert!(!matrix.any_pressed());
+        assert_eq!(board.borrow().selected, None);
+    }
+
+    #[test]
+    fn single_scan_glitch_is_ignored() {
+        let (mut matrix, board) = matrix(DiodeDirection::Col2Row);
+
+        board.borrow_mut().closed.push((0, 0));
+        assert_eq!(scan(&mut matrix, 1), []);
+        board.borrow_mut().closed.clear();
+        assert_eq!(scan(&mut matrix, 10), []);
+    }
+
+    #[test]
+    fn release_before_settling_waits_for_steady_state() {
+        let (mut matrix, board) = matrix(DiodeDirection::Col2Row);
+
+        board.borrow_mut().closed.push((0, 0));
+        assert_eq!(scan(&mut matrix, 3), [(1, event(0, 0, true))]);
+        board.borrow_mut().closed.clear();
+        assert_eq!(scan(&mut matrix, 10), [(5, event(0, 0, false))]);
+    }
+
+    #[test]
+    fn direction_decides_rows_and_columns() {
+        let (mut matrix, board) = matrix(DiodeDirection::Row2Col);
+
+        board.borrow_mut().closed.push((1, 2));
+        assert_eq!(scan(&mut matrix, 2), [(1, event(2, 1, true))]);
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let (mut matrix, board) = matrix(DiodeDirection::Col2Row);
+
+        board.borrow_mut().closed.extend([(0, 1), (1, 0)]);
+        assert_eq!(
+            scan(&mut matrix, 10),
+            [(1, event(0, 1, true)), (1, event(1, 0, true))]
+        );
+
+        board.borrow_mut().closed.retain(|key| *key != (0, 1));
+        assert_eq!(scan(&mut matrix, 10), [(1, event(0, 1, false))]);
+        assert!(matrix.any_pressed());
+    }
+}
@@ -0,0 +1,145 @@
+use defmt::Format;
+use usbd_human_interface_device::page::Keyboard;
+
+pub const PASSKEY_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PasskeyInput {
+    /// More digits are needed.
+    Pending,
+    /// Six digits were entered, as ASCII like the SoftDevice expects them.
+    Complete([u8; PASSKEY_LEN]),
+    /// The user aborted pairing with Escape.
+    Cancelled,
+}
+
+/// Collects the passkey typed on the keyboard itself during pairing.
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct PasskeyEntry {
+    digits: [u8; PASSKEY_LEN],
+    len: usize,
+}
+
+impl PasskeyEntry {
+    /// Feeds a newly pressed key. Digits from the number row and the keypad are accepted,
+    /// Backspace removes the last digit and Escape cancels, everything else is ignored.
+    pub fn push(&mut self, key: Keyboard) -> PasskeyInput {
+        match key {
+            Keyboard::Escape => return PasskeyInput::Cancelled,
+            Keyboard::DeleteBackspace => self.len = self.len.saturating_sub(1),
+            key => {
+                if let Some(digit) = digit(key) {
+                    self.digits[self.len] = b'0' + digit;
+                    self.len += 1;
+                }
+            }
+        }
+
+        if self.len == PASSKEY_LEN {
+            self.len = 0;
+            PasskeyInput::Complete(self.digits)
+        } else {
+            PasskeyInput::Pending
+        }
+    }
+}
+
+/// Usages 1-9 followed by 0, both for the number row and the keypad.
+const NUMBER_ROW_1: u8 = 0x1E;
+const KEYPAD_1: u8 = 0x59;
+
+fn digit(key: Keyboard) -> Option<u8> {
+    let usage = key as u8;
+    [NUMBER_ROW_1, KEYPAD_1]
+        .into_iter()
+        .find(|base| (*base..*base + 10).contains(&usage))
+        .map(|base| (usage - base + 1) % 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usbd_human_interface_device::page::Keyboard::*;
+
+    fn enter(keys: &[Keyboard]) -> PasskeyInput {
+        let mut entry = PasskeyEntry::default();
+        keys.iter()
+            .map(|key| entry.push(*key))
+            .last()
+            .unwrap_or(PasskeyInput::Pending)
+    }
+
+    #[test]
+    fn number_row_digits() {
+        let keys = [
+            Keyboard1, Keyboard2, Keyboard3, Keyboard4, Keyboard9, Keyboard0,
+        ];
+        assert_eq!(enter(&keys), PasskeyInput::Complete(*b"123490"));
+    }
+
+    #[test]
+    fn keypad_digits() {
+        let keys = [
+            Keypad0Insert,
+            Keypad1End,
+            Keypad5,
+            Keypad9PageUp,
+            Keyboard7,
+            Keypad8UpArrow,
+        ];
+        assert_eq!(enter(&keys), PasskeyInput::Complete(*b"015978"));
+    }
+
+    #[test]
+    fn pending_until_six_digits() {
+        let mut entry = PasskeyEntry::default();
+        for key in [Keyboard1, Keyboard2, Keyboard3, Keyboard4, Keyboard5] {
+            assert_eq!(entry.push(key), PasskeyInput::Pending);
+        }
+        assert_eq!(entry.push(Keyboard6), PasskeyInput::Complete(*b"123456"));
+        // Starts over for the next passkey.
+        assert_eq!(entry.push(Keyboard7), PasskeyInput::Pending);
+    }
+
+    #[test]
+    fn backspace_removes_last_digit() {
+        let keys = [
+            Keyboard1,
+            Keyboard2,
+            DeleteBackspace,
+            Keyboard3,
+            Keyboard4,
+            Keyboard5,
+            Keyboard6,
+            Keyboard7,
+        ];
+        assert_eq!(enter(&keys), PasskeyInput::Complete(*b"134567"));
+        assert_eq!(enter(&[DeleteBackspace]), PasskeyInput::Pending);
+    }
+
+    #[test]
+    fn escape_cancels() {
+        assert_eq!(
+            enter(&[Keyboard1, Keyboard2, Escape]),
+            PasskeyInput::Cancelled
+        );
+    }
+
+    #[test]
+    fn other_keys_are_ignored() {
+        let keys = [
+            A,
+            Keyboard1,
+            ReturnEnter,
+            Keyboard2,
+            Keyboard3,
+            LeftShift,
+            Keyboard4,
+        ];
+        assert_eq!(enter(&keys), PasskeyInput::Pending);
+        let keys = [
+            Keyboard1, A, Keyboard2, Keyboard3, Keyboard4, Keyboard5, Keyboard6,
+        ];
+        assert_eq!(enter(&keys), PasskeyInput::Complete(*b"123456"));
+    }
+}
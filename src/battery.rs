@@ -0,0 +1,62 @@
+use crate::ble::gatt::BatteryService;
+use defmt::{debug, error, info};
+use embassy_nrf::saadc::Saadc;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::Softdevice;
+
+pub use keyboard_core::battery::{BatteryConfig, BatterySource, DischargeCurve};
+
+pub const BATTERY: BatteryConfig = BatteryConfig {
+    source: BatterySource::Vddh,
+    curve: DischargeCurve::LIPO,
+    threshold: 2,
+    interval: Duration::from_secs(60),
+};
+
+/// The last reported battery level, picked up by the connection to notify the host.
+pub static BATTERY_LEVEL: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+#[embassy_executor::task]
+pub async fn battery_task(
+    sd: &'static Softdevice,
+    mut saadc: Saadc<'static, 1>,
+    bas: BatteryService,
+) {
+    info!("Battery monitor started: {}", BATTERY.source);
+    saadc.calibrate().await;
+
+    let mut reported = None;
+    loop {
+        let mut buf = [0i16; 1];
+        saadc.sample(&mut buf).await;
+
+        let millivolts = BATTERY.millivolts(buf[0]);
+        let level = BATTERY.curve.percent(millivolts);
+        debug!("Battery: {} mV, {}%", millivolts, level);
+
+        if let Some(level) = BATTERY.report(reported, level) {
+            info!("Battery level: {}%", level);
+            if let Err(e) = bas.set_level(sd, level) {
+                error!("Failed to set battery level: {}", e);
+            }
+            BATTERY_LEVEL.signal(level);
+            reported = Some(level);
+        }
+
+        Timer::after(BATTERY.interval).await;
+    }
+}
+
+/// Notifies the connected host about battery level changes, runs until the connection is dropped.
+pub async fn notify_battery_level(bas: &BatteryService, con: &Connection) {
+    loop {
+        let level = BATTERY_LEVEL.wait().await;
+        // Fails if the host didn't subscribe, the value is still readable then.
+        if let Err(e) = bas.notify_level(con, level) {
+            debug!("Battery level not notified: {}", e);
+        }
+    }
+}
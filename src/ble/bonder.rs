@@ -1,83 +1,208 @@
-use crate::kvstore::{DBKey, DBWriteError, KVStore, SerdeDB};
-use core::cell::{OnceCell, RefCell};
+use crate::kvstore::{store, DBKey, KVStore};
+use crate::passkey::{PasskeyEntry, PasskeyInput};
+use core::cell::{Cell, OnceCell, RefCell};
 use core::ops::{Deref, DerefMut};
-use defmt::{debug, info, unwrap, Format};
+use defmt::{error, info, Format};
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::channel::{Channel, Receiver};
-use nrf_softdevice::ble::gatt_server::get_sys_attrs;
+use embassy_sync::signal::Signal;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use keyboard_core::bond::{restore_sys_attrs, KnownPeersRecord, PeerRecord, SysAttrs};
+use nrf_softdevice::ble::gatt_server::{get_sys_attrs, set_sys_attrs};
 use nrf_softdevice::ble::Address;
 use nrf_softdevice::ble::{
-    gatt_server::set_sys_attrs,
-    security::{IoCapabilities, SecurityHandler},
-    Connection, EncryptionInfo, IdentityKey, MasterId,
+    security::{IoCapabilities, PasskeyReply, SecurityHandler},
+    Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
 };
+use nrf_softdevice::raw;
 use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
-use tinyvec::ArrayVec;
+use usbd_human_interface_device::page::Keyboard;
 
-#[derive(Debug, Clone, Copy, Format)]
+pub use keyboard_core::bond::{UnsupportedVersion, HOST_SLOTS};
 
+#[derive(Debug, Clone, Copy, Format, Serialize, Deserialize)]
+#[serde(from = "PeerRecord", into = "PeerRecord")]
 pub struct Peer {
     pub master_id: MasterId,
     pub key: EncryptionInfo,
     pub peer_id: IdentityKey,
     pub sys_attrs: SysAttrs,
 }
-impl DBKey for Peer {
-    fn key(&self) -> &[u8] {
-        &self.peer_id.addr.bytes
+impl From<Peer> for PeerRecord {
+    fn from(peer: Peer) -> Self {
+        Self {
+            ediv: peer.master_id.ediv,
+            rand: peer.master_id.rand,
+            ltk: peer.key.ltk,
+            ltk_flags: peer.key.flags,
+            irk: peer.peer_id.irk.as_raw().irk,
+            addr_flags: peer.peer_id.addr.flags,
+            addr: peer.peer_id.addr.bytes,
+            sys_attrs: peer.sys_attrs,
+        }
     }
 }
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
-pub struct SysAttrs(ArrayVec<[u8; 64]>);
-
-impl Deref for SysAttrs {
-    type Target = ArrayVec<[u8; 64]>;
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl From<PeerRecord> for Peer {
+    fn from(record: PeerRecord) -> Self {
+        Self {
+            master_id: MasterId {
+                ediv: record.ediv,
+                rand: record.rand,
+            },
+            key: EncryptionInfo {
+                ltk: record.ltk,
+                flags: record.ltk_flags,
+            },
+            peer_id: IdentityKey {
+                irk: IdentityResolutionKey::from_raw(raw::ble_gap_irk_t { irk: record.irk }),
+                addr: Address {
+                    flags: record.addr_flags,
+                    bytes: record.addr,
+                },
+            },
+            sys_attrs: record.sys_attrs,
+        }
     }
 }
 
-impl Format for SysAttrs {
-    fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "SysAttrs  {=[u8]:#X}", &self)
+impl DBKey for Peer {
+    fn key(&self) -> &[u8] {
+        &self.peer_id.addr.bytes
     }
 }
-
-impl DerefMut for SysAttrs {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+/// Bonder state that still has to be written to flash. Only the latest value of each is kept,
+/// so switching hosts faster than the flash is written can't overflow a queue.
+pub struct PendingStore {
+    peers: Signal<NoopRawMutex, KnownPeers>,
+    active_slot: Signal<NoopRawMutex, u8>,
 }
-type BonderChannel = Channel<NoopRawMutex, BonderMessage, 3>;
-type BonderReceiver = Receiver<'static, NoopRawMutex, BonderMessage, 3>;
 pub const BONDER: OnceCell<Bonder> = OnceCell::new();
-static CHANNEL: StaticCell<BonderChannel> = StaticCell::new();
+static PENDING_STORE: StaticCell<PendingStore> = StaticCell::new();
 pub struct Bonder {
     peer: RefCell<Option<Peer>>,
     pub known_peers: RefCell<KnownPeers>,
-    channel: &'static BonderChannel,
+    /// The host slot new bonds are stored in and advertising is targeted at.
+    active_slot: Cell<usize>,
+    /// Set while the user explicitly asked to pair a new host into the active slot.
+    pairing: Cell<bool>,
+    /// Passkey requested by the SoftDevice that is being typed on the keyboard.
+    passkey: RefCell<Option<(PasskeyReply, PasskeyEntry)>>,
+    /// Wakes the keyboard task when a passkey is requested.
+    passkey_requested: Signal<NoopRawMutex, ()>,
+    pending: &'static PendingStore,
 }
 impl Bonder {
-    pub fn new(known_peers: KnownPeers) -> Self {
-        let channel = CHANNEL.init(BonderChannel::new());
+    pub fn new(known_peers: KnownPeers, active_slot: usize) -> Self {
+        let pending = PENDING_STORE.init(PendingStore {
+            peers: Signal::new(),
+            active_slot: Signal::new(),
+        });
         Self {
             peer: RefCell::new(None),
             known_peers: RefCell::new(known_peers),
-            channel,
+            active_slot: Cell::new(active_slot.min(HOST_SLOTS - 1)),
+            pairing: Cell::new(false),
+            passkey: RefCell::new(None),
+            passkey_requested: Signal::new(),
+            pending,
+        }
+    }
+
+    pub fn active_slot(&self) -> usize {
+        self.active_slot.get()
+    }
+
+    /// The bonded host in the active slot, if there is one.
+    pub fn active_peer(&self) -> Option<Peer> {
+        self.known_peers.borrow()[self.active_slot()]
+    }
+
+    pub fn select_slot(&self, slot: usize) {
+        if slot >= HOST_SLOTS {
+            return;
+        }
+
+        info!("Selecting host slot {}", slot);
+        self.active_slot.set(slot);
+        self.pairing.set(false);
+        self.pending.active_slot.signal(slot as u8);
+    }
+
+    /// Queues the known peers to be written to flash.
+    fn store_peers(&self) {
+        self.pending.peers.signal(*self.known_peers.borrow());
+    }
+
+    /// True while key presses should go to the passkey instead of the host.
+    pub fn is_entering_passkey(&self) -> bool {
+        self.passkey.borrow().is_some()
+    }
+
+    /// Waits until the SoftDevice asks for a passkey to be entered.
+    pub async fn wait_passkey_request(&self) {
+        self.passkey_requested.wait().await
+    }
+
+    /// Feeds a key pressed during passkey entry, replying to the SoftDevice once the passkey
+    /// is complete or cancelled.
+    pub fn passkey_key(&self, key: Keyboard) {
+        let input = match self.passkey.borrow_mut().as_mut() {
+            Some((_, entry)) => entry.push(key),
+            None => return,
+        };
+
+        let passkey = match input {
+            PasskeyInput::Pending => return,
+            PasskeyInput::Complete(passkey) => Some(passkey),
+            PasskeyInput::Cancelled => None,
+        };
+
+        if let Some((reply, _)) = self.passkey.take() {
+            info!("Passkey entered: {}", passkey.is_some());
+            if let Err(e) = reply.reply(passkey.as_ref()) {
+                error!("Failed to reply with passkey: {}", e);
+            }
+        }
+    }
+
+    /// Drops a pending passkey request, rejecting the pairing.
+    pub fn cancel_passkey(&self) {
+        if let Some((reply, _)) = self.passkey.take() {
+            info!("Passkey entry cancelled");
+            reply.reply(None).ok();
         }
     }
+
+    pub fn is_pairing(&self) -> bool {
+        self.pairing.get()
+    }
+
+    /// Opens advertising to any host until a new bond is made. The bond in the active slot is
+    /// kept until the new one replaces it, so cancelling the pairing doesn't lose the host.
+    pub fn start_pairing(&self) {
+        info!("Pairing a new host into slot {}", self.active_slot());
+        self.pairing.set(true);
+    }
+
+    /// Forgets the bond stored in the active slot.
+    pub fn clear_active_slot(&self) {
+        let slot = self.active_slot();
+        info!("Clearing host slot {}", slot);
+
+        self.known_peers.borrow_mut()[slot] = None;
+        self.store_peers();
+    }
     pub fn spawn_task(&self, spawner: Spawner, db: &'static KVStore) {
-        let recv = self.channel.receiver();
-        spawner.must_spawn(bonder_task(recv, db))
+        spawner.must_spawn(bonder_task(self.pending, db))
     }
 }
 
 impl SecurityHandler for Bonder {
     fn io_capabilities(&self) -> IoCapabilities {
-        IoCapabilities::DisplayOnly // TODO Change to KeyboardOnly
+        IoCapabilities::KeyboardOnly
     }
 
     fn can_bond(&self, _conn: &Connection) -> bool {
@@ -88,6 +213,13 @@ impl SecurityHandler for Bonder {
         info!("The passkey is \"{:a}\"", passkey)
     }
 
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        info!("Type the passkey shown on the host");
+        self.cancel_passkey();
+        self.passkey.replace(Some((reply, PasskeyEntry::default())));
+        self.passkey_requested.signal(());
+    }
+
     fn on_bonded(
         &self,
         _conn: &Connection,
@@ -100,14 +232,15 @@ impl SecurityHandler for Bonder {
             master_id,
             key,
             peer_id,
-            sys_attrs: SysAttrs(ArrayVec::new()),
+            sys_attrs: SysAttrs::default(),
         };
 
-        self.known_peers.borrow_mut().add_peer(peer);
-        self.channel
-            .try_send(BonderMessage::Store(*self.known_peers.borrow()))
-            .unwrap();
+        self.known_peers
+            .borrow_mut()
+            .insert(self.active_slot(), peer);
+        self.store_peers();
         self.peer.replace(Some(peer));
+        self.pairing.set(false);
     }
 
     fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
@@ -128,75 +261,74 @@ impl SecurityHandler for Bonder {
     fn save_sys_attrs(&self, conn: &Connection) {
         info!("saving system attributes for: {}", conn.peer_address());
 
-        for peer_ref in self.known_peers.borrow_mut().iter_mut() {
-            if peer_ref.is_some() {
-                let mut peer = peer_ref.unwrap();
-                if peer.peer_id.is_match(conn.peer_address()) {
-                    let capacity = peer.sys_attrs.capacity();
-                    peer.sys_attrs.resize(capacity, 0);
-                    let len = get_sys_attrs(conn, &mut peer.sys_attrs).unwrap();
-                    peer.sys_attrs.truncate(len);
-                    *peer_ref = Some(peer);
-                    break;
-                }
-            }
+        let updated = self.known_peers.borrow_mut().save_sys_attrs(conn);
+        if updated {
+            self.store_peers();
         }
-        self.channel
-            .try_send(BonderMessage::Store(*self.known_peers.borrow()))
-            .unwrap();
     }
 
     fn load_sys_attrs(&self, conn: &Connection) {
-        let addr = conn.peer_address();
-        info!("loading system attributes for: {}", addr);
+        info!("loading system attributes for: {}", conn.peer_address());
+        self.known_peers.borrow().load_sys_attrs(conn);
+    }
+}
 
-        let attrs = self
-            .known_peers
-            .borrow()
-            .iter()
-            .filter_map(|i| *i)
-            .find(|peer| peer.peer_id.is_match(addr))
-            .filter(|i| i.sys_attrs.is_empty())
-            .map(|i| i.sys_attrs);
+#[derive(Debug, Clone, Copy, Default, Format, Serialize, Deserialize)]
+#[serde(try_from = "KnownPeersRecord", into = "KnownPeersRecord")]
+pub struct KnownPeers([Option<Peer>; HOST_SLOTS]);
 
-        unwrap!(match attrs {
-            Some(attrs) => set_sys_attrs(conn, Some(attrs.as_slice())),
-            None => set_sys_attrs(conn, None),
-        });
+impl From<KnownPeers> for KnownPeersRecord {
+    fn from(known_peers: KnownPeers) -> Self {
+        KnownPeersRecord::new(known_peers.0.map(|peer| peer.map(PeerRecord::from)))
     }
 }
 
-#[derive(Debug, Format)]
-enum BonderMessage {
-    Store(KnownPeers),
-}
+impl TryFrom<KnownPeersRecord> for KnownPeers {
+    type Error = UnsupportedVersion;
 
-#[derive(Debug, Clone, Copy, Default, Format)]
-pub struct KnownPeers([Option<Peer>; 3]);
+    fn try_from(record: KnownPeersRecord) -> Result<Self, Self::Error> {
+        let peers = record.peers()?;
+        Ok(KnownPeers(peers.map(|peer| peer.map(Peer::from))))
+    }
+}
 
 impl KnownPeers {
     pub const KEY: &'static [u8] = b"knownpeers";
+    /// Key of the host slot that was active last, restored at boot.
+    pub const ACTIVE_SLOT_KEY: &'static [u8] = b"activeslot";
 
-    fn known_peer(&self, addr: Address) -> bool {
-        self.iter()
-            .any(|i| i.is_some_and(|i| i.peer_id.is_match(addr)))
+    /// Stores the peer in the given slot, dropping it from any other slot it was bonded in.
+    fn insert(&mut self, slot: usize, peer: Peer) {
+        self.remove_peer(peer);
+        self[slot] = Some(peer);
     }
-    /// Return true if the value was inserted sucessfullt
-    /// Returns false if there is no space in the known peers
-    fn add_peer(&mut self, peer: Peer) -> bool {
-        let addr = peer.peer_id.addr;
 
-        if self.known_peer(addr) {
-            return true;
-        }
+    fn peer_mut(&mut self, addr: Address) -> Option<&mut Peer> {
+        self.iter_mut()
+            .flatten()
+            .find(|peer| peer.peer_id.is_match(addr))
+    }
 
-        for id in self.iter_mut() {
-            if id.is_none() {
-                *id = Some(peer);
-                return true;
-            }
+    /// Reads the system attributes (CCCD state) of a bonded peer from the connection.
+    /// Returns true if they changed and need to be persisted.
+    pub fn save_sys_attrs(&mut self, conn: &Connection) -> bool {
+        match self.peer_mut(conn.peer_address()) {
+            Some(peer) => peer.sys_attrs.update(|buf| get_sys_attrs(conn, buf)),
+            None => false,
         }
-        return false;
+    }
+
+    /// Restores the stored system attributes of the peer, or the defaults if the peer isn't
+    /// bonded or none were stored yet.
+    pub fn load_sys_attrs(&self, conn: &Connection) {
+        let addr = conn.peer_address();
+        let attrs = self
+            .iter()
+            .flatten()
+            .find(|peer| peer.peer_id.is_match(addr))
+            .map(|peer| &peer.sys_attrs);
+
+        restore_sys_attrs(attrs, |attrs| set_sys_attrs(conn, attrs));
     }
 
     fn remove_peer(&mut self, peer: Peer) {
@@ -211,7 +343,7 @@ impl KnownPeers {
 }
 
 impl Deref for KnownPeers {
-    type Target = [Option<Peer>; 3];
+    type Target = [Option<Peer>; HOST_SLOTS];
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -225,21 +357,26 @@ impl DerefMut for KnownPeers {
 }
 
 #[embassy_executor::task]
-pub async fn bonder_task(recv: BonderReceiver, db: &'static KVStore) {
+pub async fn bonder_task(pending: &'static PendingStore, db: &'static KVStore) {
     loop {
-        let msg = recv.receive().await;
-        info!("Got Bonder Message: {}", msg);
-        let result = match msg {
-            BonderMessage::Store(peers) => {
-
-                /*   let mut wtx = db.write_transaction().await;
-                db.write(KnownPeers::KEY, &peers, &mut wtx)
-                    .await
-                    .expect("Failed to write");
-                wtx.commit().await.expect("Failed to write")
-
-                */
+        let peers_fut = pending.peers.wait();
+        let slot_fut = pending.active_slot.wait();
+        pin_mut!(peers_fut);
+        pin_mut!(slot_fut);
+
+        let result = match select(peers_fut, slot_fut).await {
+            Either::Left((peers, _)) => {
+                info!("Storing known peers: {}", peers);
+                store(db, KnownPeers::KEY, &peers).await
+            }
+            Either::Right((slot, _)) => {
+                info!("Storing active slot: {}", slot);
+                store(db, KnownPeers::ACTIVE_SLOT_KEY, &slot).await
             }
         };
+
+        if let Err(e) = result {
+            error!("Failed to store bonder state: {}", e);
+        }
     }
 }
@@ -1,6 +1,6 @@
 use core::marker::PhantomData;
 
-use defmt::{info, Format};
+use defmt::{info, warn, Format};
 use nrf_softdevice::{
     ble::{
         gatt_server::{
@@ -15,7 +15,14 @@ use nrf_softdevice::{
     Softdevice,
 };
 use packed_struct::PackedStruct;
-use usbd_human_interface_device::device::keyboard::{BootKeyboardReport, BOOT_KEYBOARD_REPORT_DESCRIPTOR};
+
+use crate::config::{self, DEVICE_INFO, FIRMWARE_REVISION};
+use crate::hid::descriptor::{ReportInfo, ReportKind, MAX_REPORTS};
+use crate::hid::{
+    ControlPoint, Leds, ProtocolMode, HID_STATE, LEDS_REPORT_LEN, MAX_REPORT_LEN, REPORT_MAP,
+};
+use crate::keymap::{KeySet, Report};
+use crate::usb::TRANSPORT;
 
 #[derive(Debug, Clone, Copy, Format)]
 pub struct CharachteristicHandle<T: core::convert::AsRef<[u8]> + Sized> {
@@ -93,7 +100,7 @@ impl BatteryService {
             &mut service_builder,
             Uuid::new_16(0x2A19),
             Attribute::new([0u8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
+            Metadata::new(Properties::new().read().notify()),
         )?;
 
         Ok(BatteryService {
@@ -102,6 +109,14 @@ impl BatteryService {
         })
     }
 
+    pub fn set_level(&self, sd: &Softdevice, level: u8) -> Result<(), SetValueError> {
+        self.level.value_set(sd, &[level])
+    }
+
+    pub fn notify_level(&self, conn: &Connection, level: u8) -> Result<(), NotifyValueError> {
+        self.level.value_notify(conn, &[level])
+    }
+
     pub fn on_write(&self, handle: u16, data: &[u8]) {
         if handle == self.level.cccd_handle && !data.is_empty() {
             info!("battery notifications: {}", (data[0] & 0x01) != 0);
@@ -112,68 +127,68 @@ impl BatteryService {
 #[derive(Debug, Clone, Copy, Format)]
 pub struct DeviceInformationService {
     service_handle: u16,
-    manufacturer_name: CharachteristicHandle<[u8; 4]>,
-    model_number: CharachteristicHandle<[u8; 1]>,
-    serial_number: CharachteristicHandle<[u8; 1]>,
-    hardware_revision: CharachteristicHandle<[u8; 1]>,
-    firmware_revision: CharachteristicHandle<[u8; 1]>,
+    manufacturer_name: u16,
+    model_number: u16,
+    serial_number: u16,
+    hardware_revision: u16,
+    firmware_revision: u16,
     ///The SYSTEM ID characteristic consists of a structure with two fields. The first field are the LSOs and the second field contains the MSOs. This is a 64-bit structure which consists of a 40-bit manufacturer-defined identifier concatenated with a 24 bit unique Organizationally Unique Identifier (OUI). The OUI is issued by the IEEE Registration Authority (http://standards.ieee.org/regauth/index.html) and is required to be used in accordance with IEEE Standard 802-2001.6 while the least significant 40 bits are manufacturer defined. If System ID generated based on a Bluetooth Device Address, it is required to be done as follows. System ID and the Bluetooth Device Address have a very similar structure: a Bluetooth Device Address is 48 bits in length and consists of a 24 bit Company Assigned Identifier (manufacturer defined identifier) concatenated with a 24 bit Company Identifier (OUI). In order to encapsulate a Bluetooth Device Address as System ID, the Company Identifier is concatenated with 0xFFFE followed by the Company Assigned Identifier of the Bluetooth Address. For more guidelines related to EUI-64, refer to http://standards.ieee.org/develop/regauth/tut/eui64.pdf. Examples: If the system ID is based of a Bluetooth Device Address with a Company Identifier (OUI) is 0x123456 and the Company Assigned Identifier is 0x9ABCDE, then the System Identifier is required to be 0x123456FFFE9ABCDE.
-    system_id: CharachteristicHandle<[u8; 8]>,
+    system_id: u16,
     ///The PnP_ID characteristic returns its value when read using the GATT Characteristic Value Read procedure. Summary: The PnP_ID characteristic is a set of values that used to create a device ID value that is unique for this device. Included in the characteristic is a Vendor ID Source field, a Vendor ID field, a Product ID field and a Product Version field. These values are used to identify all devices of a given type/model/version using numbers.
-    pnp_id: CharachteristicHandle<[u8; 7]>,
+    pnp_id: u16,
 }
 
+/// Longest value of the string characteristics.
+const MAX_STRING_LEN: u16 = 32;
+
 impl DeviceInformationService {
     pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
         let mut service_builder = ServiceBuilder::new(sd, Uuid::new_16(0x180A))?;
 
-        let manufacturer_name = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A29),
-            Attribute::new([b'l', b'm', b'a', b'o']).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
-
-        let model_number = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A24),
-            Attribute::new([1u8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
-
-        let serial_number = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A25),
-            Attribute::new([1u8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
-
-        let hardware_revision = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A27),
-            Attribute::new([0u8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
+        let mut string = |uuid: u16, value: &[u8]| -> Result<u16, RegisterError> {
+            let value = &value[..value.len().min(MAX_STRING_LEN as usize)];
+            let handles = service_builder
+                .add_characteristic(
+                    Uuid::new_16(uuid),
+                    Attribute::new(value)
+                        .variable_len(MAX_STRING_LEN)
+                        .security(SecurityMode::Open),
+                    Metadata::new(Properties::new().read()),
+                )?
+                .build();
+            Ok(handles.value_handle)
+        };
 
-        let firmware_revision = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A26),
-            Attribute::new([0u8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
-        let system_id = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A23),
-            Attribute::new([0u8; 8]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
-
-        let pnp_id = CharachteristicHandle::new(
-            &mut service_builder,
-            Uuid::new_16(0x2A50),
-            Attribute::new([0u8; 7]).security(SecurityMode::Open),
-            Metadata::new(Properties::new().read()),
-        )?;
+        let manufacturer_name = string(0x2A29, DEVICE_INFO.manufacturer.as_bytes())?;
+        let model_number = string(0x2A24, DEVICE_INFO.model.as_bytes())?;
+        let serial = config::serial_number();
+        let serial_number = string(0x2A25, &serial)?;
+        let hardware_revision = string(0x2A27, DEVICE_INFO.hardware_revision.as_bytes())?;
+        let firmware_revision = string(0x2A26, FIRMWARE_REVISION.as_bytes())?;
+
+        let system_id = service_builder
+            .add_characteristic(
+                Uuid::new_16(0x2A23),
+                Attribute::new(config::system_id()).security(SecurityMode::Open),
+                Metadata::new(Properties::new().read()),
+            )?
+            .build()
+            .value_handle;
+
+        let pnp_id = service_builder
+            .add_characteristic(
+                Uuid::new_16(0x2A50),
+                Attribute::new(DEVICE_INFO.pnp_id()).security(SecurityMode::Open),
+                Metadata::new(Properties::new().read()),
+            )?
+            .build()
+            .value_handle;
+
+        info!(
+            "Device information: firmware {=str}, serial {=[u8]:a}",
+            FIRMWARE_REVISION,
+            serial.as_slice()
+        );
 
         Ok(Self {
             service_handle: service_builder.build().handle(),
@@ -187,13 +202,25 @@ impl DeviceInformationService {
         })
     }
 }
+/// A report characteristic registered for one entry of the report map.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct ReportCharacteristic {
+    pub info: ReportInfo,
+    value_handle: u16,
+}
+
 #[derive(Clone, Format)]
 ///This service exposes the HID reports and other HID data intended for HID Hosts and HID Devices. Summary: The HID Service exposes characteristics required for a HID Device to transfer HID report descriptors and reports to a HID Host. This also exposes the characteristics for a HID Host to write to a Device. The Human Interface Device Service is instantiated as a Primary Service.
 pub struct HIDService {
     service_handle: u16,
     protocol_mode: CharachteristicHandle<[u8; 1]>,
-    pub report: CharachteristicHandle<[u8; 8]>,
-    report_map: CharachteristicHandle<[u8; 65]>,
+    /// One characteristic per input and output report of `REPORT_MAP`.
+    reports: [Option<ReportCharacteristic>; MAX_REPORTS],
+    /// Boot Keyboard Input Report, used instead of the report map while in boot protocol.
+    pub boot_input: CharachteristicHandle<[u8; 8]>,
+    /// Boot Keyboard Output Report, the LED state while in boot protocol.
+    boot_output: CharachteristicHandle<[u8; LEDS_REPORT_LEN]>,
+    report_map_handle: u16,
     hid_information: CharachteristicHandle<[u8; 4]>,
     hid_control_point: CharachteristicHandle<[u8; 1]>,
 }
@@ -205,36 +232,50 @@ impl HIDService {
         let protocol_mode = CharachteristicHandle::new(
             &mut service_builder,
             Uuid::new_16(0x2A4E),
-            Attribute::new([0x01u8]).security(SecurityMode::Open),
+            Attribute::new([ProtocolMode::Report as u8]).security(SecurityMode::Open),
             Metadata::new(Properties::new().read().write_without_response()),
         )?;
 
-        let mut x = service_builder.add_characteristic(
-            Uuid::new_16(0x2A4D),
-            Attribute::new(BootKeyboardReport::default().pack().unwrap_or_default())
-                .security(SecurityMode::Mitm),
-            Metadata::new(Properties::new().notify().read().write()),
+        let mut reports = [None; MAX_REPORTS];
+        for (slot, info) in reports.iter_mut().zip(REPORT_MAP.reports()) {
+            let properties = match info.kind {
+                ReportKind::Input => Properties::new().read().notify(),
+                ReportKind::Output => Properties::new().read().write().write_without_response(),
+            };
+
+            let mut x = service_builder.add_characteristic(
+                Uuid::new_16(0x2A4D),
+                Attribute::new(&[0u8; MAX_REPORT_LEN][..info.len]).security(SecurityMode::Mitm),
+                Metadata::new(properties),
+            )?;
+            x.add_descriptor(
+                Uuid::new_16(0x2908),
+                Attribute::new([info.id, info.kind as u8]),
+            )?;
+
+            *slot = Some(ReportCharacteristic {
+                info,
+                value_handle: x.build().value_handle,
+            });
+        }
+
+        let boot_input = CharachteristicHandle::new(
+            &mut service_builder,
+            Uuid::new_16(0x2A22),
+            Attribute::new([0u8; 8]).security(SecurityMode::Mitm),
+            Metadata::new(Properties::new().notify().read()),
+        )?;
+
+        let boot_output = CharachteristicHandle::new(
+            &mut service_builder,
+            Uuid::new_16(0x2A32),
+            Attribute::new([0u8; LEDS_REPORT_LEN]).security(SecurityMode::Mitm),
+            Metadata::new(Properties::new().read().write().write_without_response()),
         )?;
 
-        /*
-                let client_characteristic_configuration = x.add_descriptor(
-                    Uuid::new_16(2902),
-                    Attribute::new([0x0, 0x00])
-                        .security(SecurityMode::Mitm)
-                        .write_security(SecurityMode::Mitm),
-                )?;
-        */
-        let report_reference =
-            x.add_descriptor(Uuid::new_16(0x2908), Attribute::new([0x0, 0x01]))?;
-
-        let report = x.build();
-                
         let mut x = service_builder.add_characteristic(
             Uuid::new_16(0x2A4B),
-            Attribute::new(
-                BOOT_KEYBOARD_REPORT_DESCRIPTOR,
-            )
-            .security(SecurityMode::Mitm),
+            Attribute::new(REPORT_MAP.bytes()).security(SecurityMode::Mitm),
             Metadata::new(Properties::new().read()),
         )?;
         let external_report_reference = x.add_descriptor(
@@ -261,14 +302,96 @@ impl HIDService {
         Ok(Self {
             service_handle: service_builder.build().handle(),
             protocol_mode,
-            report: report.into(),
-            report_map: report_map.into(),
+            reports,
+            boot_input,
+            boot_output,
+            report_map_handle: report_map.value_handle,
             hid_information,
             hid_control_point,
         })
     }
 
-    pub fn on_write(&self, handle: u16, data: &[u8]) {}
+    /// Puts the service back into report protocol for a new connection. The LEDs are left to
+    /// the USB host while reports are routed there.
+    pub fn reset(&self, sd: &Softdevice) -> Result<(), SetValueError> {
+        HID_STATE.reset();
+        if !TRANSPORT.uses_usb() {
+            HID_STATE.clear_leds();
+        }
+        self.protocol_mode
+            .value_set(sd, &[ProtocolMode::Report as u8])
+    }
+
+    /// The characteristic of a report in the report map.
+    pub fn report(&self, id: u8, kind: ReportKind) -> Option<ReportCharacteristic> {
+        self.reports
+            .iter()
+            .flatten()
+            .find(|report| report.info.id == id && report.info.kind == kind)
+            .copied()
+    }
+
+    fn is_output_report(&self, handle: u16) -> bool {
+        self.reports
+            .iter()
+            .flatten()
+            .any(|report| report.info.kind == ReportKind::Output && report.value_handle == handle)
+    }
+
+    /// Notifies the held keys in the format matching the host's protocol mode.
+    pub fn send_keys(&self, conn: &Connection, keys: &KeySet) -> Result<(), NotifyValueError> {
+        self.send_report(conn, &Report::Keyboard(*keys))
+    }
+
+    /// Notifies a report queued by the keymap. Boot protocol only has a keyboard report, so
+    /// all other reports are dropped while in it, as are reports missing from the report map.
+    pub fn send_report(&self, conn: &Connection, report: &Report) -> Result<(), NotifyValueError> {
+        if HID_STATE.protocol_mode() == ProtocolMode::Boot {
+            return match report {
+                Report::Keyboard(keys) => {
+                    let report = keys.boot_report().pack().unwrap_or_default();
+                    self.boot_input.value_notify(conn, &report)
+                }
+                _ => Ok(()),
+            };
+        }
+
+        let mut buf = [0u8; MAX_REPORT_LEN];
+        let Some((id, len)) = REPORT_MAP.encode(report, &mut buf) else {
+            return Ok(());
+        };
+        match self.report(id, ReportKind::Input) {
+            Some(handle) => gatt_server::notify_value(conn, handle.value_handle, &buf[..len]),
+            None => Ok(()),
+        }
+    }
+
+    pub fn on_write(&self, handle: u16, data: &[u8]) {
+        let Some(&value) = data.first() else {
+            return;
+        };
+
+        if handle == self.protocol_mode.value_handle {
+            match ProtocolMode::from_u8(value) {
+                Some(mode) => {
+                    info!("HID protocol mode: {}", mode);
+                    HID_STATE.set_protocol_mode(mode);
+                }
+                None => warn!("Invalid HID protocol mode: {}", value),
+            }
+        } else if handle == self.boot_output.value_handle || self.is_output_report(handle) {
+            info!("Host LEDs: {=u8:#04x}", value);
+            HID_STATE.set_leds(Leds(value));
+        } else if handle == self.hid_control_point.value_handle {
+            match ControlPoint::from_u8(value) {
+                Some(command) => {
+                    info!("HID control point: {}", command);
+                    HID_STATE.set_suspended(command == ControlPoint::Suspend);
+                }
+                None => warn!("Invalid HID control point command: {}", value),
+            }
+        }
+    }
 }
 #[derive(Clone, Format)]
 pub struct GATTServer {
@@ -5,6 +5,7 @@ use self::gatt::GATTServer;
 pub mod bonder;
 pub mod gatt;
 pub mod softdevice;
+pub use keyboard_core::advertising;
 
 
 pub const HID_SERVICE: u16 = 0x1812;
@@ -1,32 +1,34 @@
 use super::{
+    advertising::{AdField, AdvError, AdvPayload},
     bonder::{Bonder, KnownPeers},
     gatt::GATTServer,
     BATTERY_SERVICE, DEVICE_INFO_SERVICE, HID_SERVICE,
 };
+use crate::config::{DeviceConfig, MAX_NAME_LEN};
+use crate::keymap::action::HostAction;
 use crate::kvstore::{DBReadError, KVStore, SerdeDB};
-use alloc::{
-    string::{String, ToString},
-    vec::Vec,
-};
+use crate::usb;
+use alloc::{string::String, vec::Vec};
 use defmt::{error, info};
 use embassy_executor::Spawner;
+use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
 use nrf_softdevice::{
     ble::{
         self,
-        peripheral::{self, AdvertiseError},
+        peripheral::{self, AdvertiseError, FilterPolicy},
         Address, Connection, IdentityKey, IdentityResolutionKey,
     },
     raw, Softdevice,
 };
-use nrf_softdevice_s140::*;
-use serde::{Deserialize, Serialize};
 
 #[embassy_executor::task]
-pub async fn softdevice_task(sd: &'static Softdevice) -> ! {
-    sd.run().await
+pub async fn softdevice_task(sd: &'static Softdevice, vbus: &'static SoftwareVbusDetect) -> ! {
+    sd.run_with_callback(|event| usb::on_soc_event(vbus, event))
+        .await
 }
 
-fn softdevice_config() -> nrf_softdevice::Config {
+/// The SoftDevice copies the device name while it is enabled, `device` only has to outlive that.
+fn softdevice_config(device: &DeviceConfig) -> nrf_softdevice::Config {
     nrf_softdevice::Config {
         clock: Some(raw::nrf_clock_lf_cfg_t {
             source: raw::NRF_CLOCK_LF_SRC_RC as u8,
@@ -39,6 +41,10 @@ fn softdevice_config() -> nrf_softdevice::Config {
             event_length: 24,
         }),
         conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 1024 }),
+        // Room for the reports of a macro or a burst of key events between connection events.
+        conn_gatts: Some(raw::ble_gatts_conn_cfg_t {
+            hvn_tx_queue_size: 8,
+        }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
             attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT,
         }),
@@ -56,141 +62,209 @@ fn softdevice_config() -> nrf_softdevice::Config {
             _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(
                 raw::BLE_GATTS_VLOC_STACK as u8,
             ),
-            p_value: b"Rust Keyboard" as *const u8 as _,
-            current_len: 13,
-            max_len: 13,
+            p_value: device.name.as_ptr() as _,
+            current_len: device.name.len() as u16,
+            max_len: MAX_NAME_LEN as u16,
         }),
         ..Default::default()
     }
 }
 
 pub async fn sync_peers(sd: &Softdevice, db: &'static KVStore) -> KnownPeers {
-    /*
-    let known_peers: Result<KnownPeers, DBReadError> = db.read(KnownPeers::KEY).await;
-
-    if let Err(ref e) = known_peers {
-        error!("{}", e);
-        match e {
-            DBReadError::IO(ref e) => match e {
-                ekv::ReadError::KeyNotFound => {
-                    let mut wtx = db.write_transaction().await;
-                    db.write(KnownPeers::KEY, &KnownPeers::default(), &mut wtx)
-                        .await;
-                    wtx.commit().await.expect("Failed to commit");
-                }
-                _ => panic!("DB Error"),
-            },
-            _ => {}
+    let known_peers = match db.read::<KnownPeers>(KnownPeers::KEY).await {
+        Ok(known_peers) => known_peers,
+        Err(DBReadError::IO(ekv::ReadError::KeyNotFound)) => {
+            info!("No stored peers, starting without bonds");
+            KnownPeers::default()
         }
-    }
-    */
+        Err(DBReadError::Deserialize(e)) => {
+            error!("Stored peers could not be decoded, discarding them: {}", e);
+            KnownPeers::default()
+        }
+        Err(DBReadError::IO(e)) => panic!("Failed to read known peers: {}", e),
+    };
 
-    let known_peers = KnownPeers::default();
     info!("Known Peers: {}", known_peers);
+    sync_identities(sd, &known_peers);
+
+    known_peers
+}
+
+/// Registers the identity keys of all bonded peers with the SoftDevice, so peers using
+/// resolvable private addresses are recognized and can pass the whitelist.
+///
+/// Every peer is paired with our own device IRK, the one handed out during bonding.
+pub fn sync_identities(sd: &Softdevice, known_peers: &KnownPeers) {
     let id_keys: Vec<IdentityKey> = known_peers
         .iter()
         .filter_map(|i| *i)
         .map(|peer| peer.peer_id)
         .collect();
+    let local_irks: Option<Vec<IdentityResolutionKey>> =
+        device_irk().map(|irk| id_keys.iter().map(|_| irk).collect());
 
-    let irks: Vec<IdentityResolutionKey> = known_peers
-        .iter()
-        .filter_map(|i| *i)
-        .map(|i| i.peer_id.irk)
-        .collect();
-    // ble::set_device_identities_list(&sd, id_keys.as_slice(), Some(irks.as_slice())).unwrap();
+    if let Err(e) = ble::set_device_identities_list(sd, id_keys.as_slice(), local_irks.as_deref()) {
+        error!("Failed to set device identities: {}", e);
+    }
+}
 
-    let addrs: Vec<Address> = known_peers
-        .iter()
-        .filter_map(|i| *i)
-        .map(|p| p.peer_id.addr)
-        .collect();
-    //ble::set_whitelist(&sd, addrs.as_slice()).expect("Failed");
+/// The IRK the SoftDevice distributes to peers when bonding.
+fn device_irk() -> Option<IdentityResolutionKey> {
+    let mut irk = raw::ble_gap_irk_t { irk: [0; 16] };
+    let mut params: raw::ble_gap_privacy_params_t = unsafe { core::mem::zeroed() };
+    params.p_device_irk = &mut irk;
 
-    known_peers
+    let ret = unsafe { raw::sd_ble_gap_privacy_get(&mut params) };
+    if ret != raw::NRF_SUCCESS {
+        error!("Failed to get device IRK: {}", ret);
+        return None;
+    }
+    Some(IdentityResolutionKey::from_raw(irk))
 }
 
 pub async fn init(
     spawner: Spawner,
     db: &'static KVStore,
-) -> (&'static Softdevice, GATTServer, Bonder, AdvData) {
-    let adv_data: AdvData = db.read(AdvData::KEY).await.unwrap_or_default();
-    let config = softdevice_config();
+    vbus: &'static SoftwareVbusDetect,
+    device: &DeviceConfig,
+) -> (&'static Softdevice, GATTServer, Bonder, AdvPayload) {
+    let config = softdevice_config(device);
     let sd = Softdevice::enable(&config);
+    usb::enable_power_events(vbus);
+
+    let ret = unsafe { raw::sd_ble_gap_appearance_set(device.appearance) };
+    if ret != raw::NRF_SUCCESS {
+        error!("Failed to set appearance: {}", ret);
+    }
 
     let server = GATTServer::new(sd).expect("failed to create GATT server");
     let known_peers = sync_peers(&sd, db).await;
 
-    info!("Known Peers {}", known_peers);
-    let bonder = Bonder::new(known_peers);
+    let active_slot: u8 = db
+        .read(KnownPeers::ACTIVE_SLOT_KEY)
+        .await
+        .unwrap_or_default();
+
+    info!("Known Peers {}, active slot {}", known_peers, active_slot);
+    let bonder = Bonder::new(known_peers, active_slot as usize);
 
     bonder.spawn_task(spawner, db);
-    spawner.must_spawn(softdevice_task(sd));
+    spawner.must_spawn(softdevice_task(sd, vbus));
 
-    (sd, server, bonder, adv_data)
+    let adv = AdvData::new(device)
+        .encode()
+        .expect("invalid advertising data");
+
+    (sd, server, bonder, adv)
 }
 
 pub async fn advertise(
     sd: &Softdevice,
-    adv: &AdvData,
+    adv: &AdvPayload,
     bonder: &'static Bonder,
 ) -> Result<Connection, AdvertiseError> {
-    let config = peripheral::Config::default();
-    let adv = adv.to_bytes();
-    let adv =
-        peripheral::ConnectableAdvertisement::ScannableUndirected {
-            adv_data: adv.as_slice(),
-            scan_data: &[],
-        };
+    let mut config = peripheral::Config::default();
+    let known_peers = *bonder.known_peers.borrow();
+    sync_identities(sd, &known_peers);
+
+    // Only the host bonded in the active slot may connect. Advertise openly in pairing mode or
+    // when the slot is empty, so a new host can bond into it.
+    let whitelist: Vec<Address> = match bonder.active_peer() {
+        Some(peer) if !bonder.is_pairing() => [peer.peer_id.addr].to_vec(),
+        _ => Vec::new(),
+    };
+
+    if whitelist.is_empty() {
+        info!("Advertising for pairing in slot {}", bonder.active_slot());
+    } else {
+        info!("Advertising to the host in slot {}", bonder.active_slot());
+        match ble::set_whitelist(sd, whitelist.as_slice()) {
+            Ok(()) => config.filter_policy = FilterPolicy::Both,
+            // Still reachable for the bonded host, just without filtering other scanners.
+            Err(e) => error!("Failed to set whitelist, advertising unfiltered: {}", e),
+        }
+    }
+
+    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+        adv_data: adv.adv_data.as_bytes(),
+        scan_data: adv.scan_data.as_bytes(),
+    };
 
     info!("Advertising Started");
     peripheral::advertise_pairable(sd, adv, &config, bonder).await
 }
 
+/// Carries out a host profile key. The caller restarts advertising afterwards, so the
+/// current connection, if any, is dropped.
+pub fn apply_host_action(bonder: &Bonder, action: HostAction, con: Option<&Connection>) {
+    info!("Host action: {}", action);
+
+    match action {
+        HostAction::Select(slot) => bonder.select_slot(slot as usize),
+        HostAction::Clear => bonder.clear_active_slot(),
+        HostAction::Pair => bonder.start_pairing(),
+    }
+
+    if let Some(con) = con {
+        if let Err(e) = con.disconnect() {
+            error!("Failed to disconnect: {}", e);
+        }
+    }
+}
+
 /// https://infocenter.nordicsemi.com/topic/com.nordic.infocenter.s140.api.v7.3.0/group___b_l_e___g_a_p___a_d___t_y_p_e___d_e_f_i_n_i_t_i_o_n_s.html?cp=5_7_4_1_2_1_1_5
 /// https://bitbucket.org/bluetooth-SIG/public/src/main/assigned_numbers/
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct AdvData {
     pub flags: u8,
     pub uuids: Vec<u16>,
+    /// 128-bit service UUIDs in little endian byte order.
+    pub uuids_128: Vec<[u8; 16]>,
     pub name: String,
-    pub appearance: u8,
+    pub appearance: u16,
+    pub tx_power: Option<i8>,
+    /// Company identifier and data.
+    pub manufacturer_data: Option<(u16, Vec<u8>)>,
 }
 
-impl Default for AdvData {
-    fn default() -> Self {
+impl AdvData {
+    pub fn new(device: &DeviceConfig) -> Self {
         AdvData {
             flags: raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-            name: "HelloWorld".to_string(),
+            name: device.name.clone(),
             uuids: [HID_SERVICE, DEVICE_INFO_SERVICE, BATTERY_SERVICE].to_vec(),
-            appearance: BLE_APPEARANCE_HID_KEYBOARD as u8,
+            uuids_128: Vec::new(),
+            appearance: device.appearance,
+            tx_power: None,
+            manufacturer_data: None,
         }
     }
-}
 
-impl AdvData {
-    pub const KEY: &'static [u8] = b"AdvData";
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = Vec::new();
-        data.push(2);
-        data.push(BLE_GAP_AD_TYPE_APPEARANCE as u8);
-        data.push(self.appearance);
-
-        //flags
-        data.extend_from_slice(&[0x02, BLE_GAP_AD_TYPE_FLAGS as u8, self.flags]);
-
-        //service uuids
-        data.push(self.uuids.len() as u8 * 2 + 1);
-        data.push(BLE_GAP_AD_TYPE_16BIT_SERVICE_UUID_COMPLETE as u8);
-        self.uuids
-            .iter()
-            .for_each(|uuid| data.extend_from_slice(&uuid.to_le_bytes()));
-
-        //name
-        data.push(self.name.len() as u8 + 1);
-        data.push(BLE_GAP_AD_TYPE_COMPLETE_LOCAL_NAME as u8);
-        data.extend_from_slice(self.name.as_bytes());
-
-        data
+    /// Builds the advertising data and scan response. The name is moved to the scan response
+    /// or shortened if the other fields leave no room for it.
+    pub fn encode(&self) -> Result<AdvPayload, AdvError> {
+        let mut fields: Vec<AdField> = [
+            AdField::Flags(self.flags),
+            AdField::Appearance(self.appearance),
+        ]
+        .to_vec();
+
+        if !self.uuids.is_empty() {
+            fields.push(AdField::Uuids16(&self.uuids));
+        }
+        if !self.uuids_128.is_empty() {
+            fields.push(AdField::Uuids128(&self.uuids_128));
+        }
+        if let Some(power) = self.tx_power {
+            fields.push(AdField::TxPower(power));
+        }
+        if let Some((company, data)) = &self.manufacturer_data {
+            fields.push(AdField::ManufacturerData {
+                company: *company,
+                data,
+            });
+        }
+
+        AdvPayload::new(&fields, &self.name)
     }
 }
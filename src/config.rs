@@ -0,0 +1,294 @@
+use crate::keymap::leader::LeaderSequence;
+use crate::keymap::macros::{Macro, MAX_MACROS, RECORDED_MACROS};
+use crate::kvstore::{store, DBReadError, KVStore, SerdeDB};
+use crate::layout;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use defmt::{error, info, warn};
+use embassy_nrf::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use nrf_softdevice_s140::BLE_APPEARANCE_HID_KEYBOARD;
+use serde::{Deserialize, Serialize};
+
+/// Longest device name the GAP Device Name characteristic can hold.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Crate version and the git commit it was built from.
+pub const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("GIT_HASH"));
+
+/// Fixed identity of the device, reported through USB and the Device Information Service.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    pub manufacturer: &'static str,
+    pub model: &'static str,
+    pub hardware_revision: &'static str,
+    /// Vendor ID source of the PnP ID, 0x01 for Bluetooth SIG and 0x02 for USB-IF.
+    pub vendor_id_source: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// pid.codes test VID/PID.
+pub const DEVICE_INFO: DeviceInfo = DeviceInfo {
+    manufacturer: "nrf-keyboard",
+    model: "Rust Keyboard",
+    hardware_revision: "1",
+    vendor_id_source: 0x02,
+    vendor_id: 0x1209,
+    product_id: 0x0001,
+};
+
+impl DeviceInfo {
+    /// Product version of the PnP ID, the crate version as 0xJJMN like USB bcdDevice.
+    pub fn product_version(&self) -> u16 {
+        let part = |s: &str| s.parse::<u16>().unwrap_or(0);
+        let major = part(env!("CARGO_PKG_VERSION_MAJOR")) & 0xFF;
+        let minor = part(env!("CARGO_PKG_VERSION_MINOR")) & 0x0F;
+        let patch = part(env!("CARGO_PKG_VERSION_PATCH")) & 0x0F;
+        (major << 8) | (minor << 4) | patch
+    }
+
+    pub fn pnp_id(&self) -> [u8; 7] {
+        let [vid_lo, vid_hi] = self.vendor_id.to_le_bytes();
+        let [pid_lo, pid_hi] = self.product_id.to_le_bytes();
+        let [ver_lo, ver_hi] = self.product_version().to_le_bytes();
+        [
+            self.vendor_id_source,
+            vid_lo,
+            vid_hi,
+            pid_lo,
+            pid_hi,
+            ver_lo,
+            ver_hi,
+        ]
+    }
+}
+
+/// The factory programmed 64-bit device ID as 16 hex digits.
+pub fn serial_number() -> [u8; 16] {
+    let ficr = unsafe { &*pac::FICR::ptr() };
+    let id =
+        ((ficr.deviceid[1].read().bits() as u64) << 32) | ficr.deviceid[0].read().bits() as u64;
+
+    let mut serial = [0u8; 16];
+    for (i, digit) in serial.iter_mut().enumerate() {
+        let nibble = (id >> ((15 - i) * 4)) as u8 & 0x0F;
+        *digit = b"0123456789ABCDEF"[nibble as usize];
+    }
+    serial
+}
+
+/// System ID derived from the factory programmed device address, little endian.
+pub fn system_id() -> [u8; 8] {
+    let ficr = unsafe { &*pac::FICR::ptr() };
+    let low = ficr.deviceaddr[0].read().bits().to_le_bytes();
+    let high = ficr.deviceaddr[1].read().bits().to_le_bytes();
+    system_id_from_address([low[0], low[1], low[2], low[3], high[0], high[1]])
+}
+
+/// Encapsulates a little endian device address as System ID: the upper 24 bits (OUI) are
+/// followed by 0xFFFE and the lower 24 bits.
+pub fn system_id_from_address(address: [u8; 6]) -> [u8; 8] {
+    [
+        address[0], address[1], address[2], 0xFE, 0xFF, address[3], address[4], address[5],
+    ]
+}
+
+/// Device settings persisted in the KV store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// GAP device name, also advertised as the local name.
+    pub name: String,
+    /// GAP appearance, see the Bluetooth assigned numbers.
+    pub appearance: u16,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            name: "Rust Keyboard".to_string(),
+            appearance: BLE_APPEARANCE_HID_KEYBOARD as u16,
+        }
+    }
+}
+
+/// What is actually stored under `DeviceConfig::KEY`. The version comes first, so it can be
+/// read on its own before the rest is decoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredConfig {
+    version: u8,
+    config: DeviceConfig,
+}
+
+/// The leading version of a `StoredConfig`, postcard ignores the bytes that follow it.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredVersion {
+    version: u8,
+}
+
+impl StoredConfig {
+    /// Bumped whenever the layout of `DeviceConfig` changes.
+    const VERSION: u8 = 1;
+}
+
+impl DeviceConfig {
+    pub const KEY: &'static [u8] = b"deviceconfig";
+
+    /// Loads the stored config, falling back to the defaults if there is none or it can't be
+    /// used. The defaults are only written if nothing was stored, a config of an unknown
+    /// version, e.g. from newer firmware, or one that can't be decoded is left untouched.
+    pub async fn load(db: &KVStore) -> DeviceConfig {
+        let config = match db.read::<StoredVersion>(Self::KEY).await {
+            Ok(StoredVersion {
+                version: StoredConfig::VERSION,
+            }) => Self::read_current(db).await,
+            // Older versions are upgraded here once the layout changes.
+            Ok(StoredVersion { version }) => {
+                error!(
+                    "Stored device config has unsupported version {}, using defaults",
+                    version
+                );
+                DeviceConfig::default()
+            }
+            Err(DBReadError::IO(ekv::ReadError::KeyNotFound)) => {
+                info!("No stored device config, storing defaults");
+                let config = DeviceConfig::default();
+                config.save(db).await;
+                config
+            }
+            Err(DBReadError::Deserialize(e)) => {
+                error!(
+                    "Stored device config version could not be decoded, using defaults: {}",
+                    e
+                );
+                DeviceConfig::default()
+            }
+            Err(DBReadError::IO(e)) => panic!("Failed to read device config: {}", e),
+        };
+
+        let config = config.sanitized();
+        info!(
+            "Device config: name {=str}, appearance {=u16:#06x}",
+            config.name.as_str(),
+            config.appearance
+        );
+        config
+    }
+
+    /// Reads a config stored in the current version.
+    async fn read_current(db: &KVStore) -> DeviceConfig {
+        match db.read::<StoredConfig>(Self::KEY).await {
+            Ok(stored) => stored.config,
+            Err(DBReadError::Deserialize(e)) => {
+                error!(
+                    "Stored device config could not be decoded, using defaults: {}",
+                    e
+                );
+                DeviceConfig::default()
+            }
+            Err(DBReadError::IO(e)) => panic!("Failed to read device config: {}", e),
+        }
+    }
+
+    pub async fn save(&self, db: &KVStore) {
+        let stored = StoredConfig {
+            version: StoredConfig::VERSION,
+            config: self.clone(),
+        };
+        if let Err(e) = store(db, Self::KEY, &stored).await {
+            error!("Failed to store device config: {}", e);
+        }
+    }
+
+    /// Cuts the name down to `MAX_NAME_LEN` bytes, on a character boundary.
+    fn sanitized(mut self) -> Self {
+        if self.name.len() > MAX_NAME_LEN {
+            let end = (0..=MAX_NAME_LEN)
+                .rev()
+                .find(|&i| self.name.is_char_boundary(i))
+                .unwrap_or(0);
+            self.name.truncate(end);
+        }
+        if self.name.is_empty() {
+            self.name = DeviceConfig::default().name;
+        }
+        self
+    }
+}
+
+/// KV store key of the leader sequences, written by the host tools that edit the keymap.
+pub const LEADER_KEY: &[u8] = b"leader";
+
+/// Loads the stored leader sequences, falling back to `layout::leader_sequences` if there are
+/// none or they can't be decoded. The defaults aren't written back, so new firmware defaults
+/// take effect until sequences are stored.
+pub async fn load_leader_sequences(db: &KVStore) -> Vec<LeaderSequence> {
+    match db.read::<Vec<LeaderSequence>>(LEADER_KEY).await {
+        Ok(sequences) => {
+            info!("Loaded {} leader sequences", sequences.len());
+            sequences
+        }
+        Err(DBReadError::IO(ekv::ReadError::KeyNotFound)) => layout::leader_sequences(),
+        Err(DBReadError::Deserialize(e)) => {
+            error!(
+                "Stored leader sequences could not be decoded, using defaults: {}",
+                e
+            );
+            layout::leader_sequences()
+        }
+        Err(DBReadError::IO(e)) => panic!("Failed to read leader sequences: {}", e),
+    }
+}
+
+/// KV store key of the macro with the given ID.
+pub fn macro_key(id: u8) -> [u8; 6] {
+    let mut key = *b"macro\0";
+    key[5] = id;
+    key
+}
+
+/// Loads the macros, one entry per ID up to `MAX_MACROS`. Recorded IDs get their stored
+/// macro, all others the one from `layout::macros`, or an empty one.
+pub async fn load_macros(db: &KVStore) -> Vec<Macro> {
+    let mut macros = layout::macros();
+    macros.resize(MAX_MACROS, Macro::default());
+
+    for id in RECORDED_MACROS {
+        let slot = &mut macros[id as usize];
+        match db.read::<Macro>(macro_key(id)).await {
+            Ok(stored) => *slot = stored,
+            Err(DBReadError::IO(ekv::ReadError::KeyNotFound)) => {}
+            Err(DBReadError::Deserialize(e)) => {
+                error!(
+                    "Stored macro {} could not be decoded, using default: {}",
+                    id, e
+                );
+            }
+            Err(DBReadError::IO(e)) => panic!("Failed to read macro {}: {}", id, e),
+        }
+    }
+    macros
+}
+
+/// Recorded macros waiting to be stored, see `macro_store_task`.
+static SAVE_MACRO: Channel<CriticalSectionRawMutex, (u8, Macro), 4> = Channel::new();
+
+/// Queues a recorded macro to be stored under its ID.
+pub fn save_macro(id: u8, recorded: Macro) {
+    if SAVE_MACRO.try_send((id, recorded)).is_err() {
+        warn!("Macro store queue full, dropping macro {}", id);
+    }
+}
+
+/// Writes recorded macros to the store, so the keyboard task doesn't wait for the flash.
+#[embassy_executor::task]
+pub async fn macro_store_task(db: &'static KVStore) {
+    loop {
+        let (id, recorded) = SAVE_MACRO.receive().await;
+        info!("Storing macro {} with {} steps", id, recorded.steps.len());
+        if let Err(e) = store(db, &macro_key(id), &recorded).await {
+            error!("Failed to store macro {}: {}", id, e);
+        }
+    }
+}
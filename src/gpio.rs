@@ -1,33 +1,105 @@
-use crate::ble::gatt::GATTServer;
-use defmt::info;
-use embassy_nrf::gpio::{AnyPin, Input, Pin as _, Pull};
-use nrf_softdevice::ble::Connection;
-use packed_struct::PackedStruct;
-use usbd_human_interface_device::{device::keyboard::BootKeyboardReport, page::Keyboard};
-pub async fn button_task(
-    n: usize,
-    pin: &mut Input<'static, AnyPin>,
-    gatt: &GATTServer,
-    con: &Connection,
-) {
+use crate::hid::{Led, HID_STATE};
+use crate::matrix::{KeyEvent, Matrix, MatrixInput, MatrixOutput};
+use defmt::{debug, info};
+use embassy_nrf::gpio::{AnyPin, Input, Output};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_time::{Duration, Timer};
+use static_cell::StaticCell;
+
+pub const ROWS: usize = 4;
+pub const COLS: usize = 12;
+/// Time between two full scans of the matrix.
+pub const SCAN_INTERVAL: Duration = Duration::from_millis(1);
+/// Time between two full scans while the host has suspended the HID service.
+pub const SUSPENDED_SCAN_INTERVAL: Duration = Duration::from_millis(20);
+/// CPU cycles to wait after driving an output so the input lines can settle.
+const SETTLE_CYCLES: u32 = 64;
+
+/// The board is wired ROW2COL, so the columns are driven and the rows are read.
+pub type KeyMatrix = Matrix<Input<'static, AnyPin>, Output<'static, AnyPin>, ROWS, COLS>;
+
+/// A host LED mirrored on an indicator pin.
+#[derive(Debug, Clone, Copy)]
+pub struct IndicatorConfig {
+    pub led: Led,
+    /// True if driving the pin high lights the indicator.
+    pub active_high: bool,
+}
+
+pub const INDICATORS: [IndicatorConfig; 1] = [IndicatorConfig {
+    led: Led::CapsLock,
+    active_high: true,
+}];
+
+/// Indicator pins, in the order of `INDICATORS`.
+pub type Indicators = [Output<'static, AnyPin>; INDICATORS.len()];
+
+pub type KeyEventChannel = Channel<NoopRawMutex, KeyEvent, 16>;
+pub type KeyEventSender = Sender<'static, NoopRawMutex, KeyEvent, 16>;
+pub type KeyEventReceiver = Receiver<'static, NoopRawMutex, KeyEvent, 16>;
+
+static KEY_EVENTS: StaticCell<KeyEventChannel> = StaticCell::new();
+
+pub fn init_key_events() -> &'static KeyEventChannel {
+    KEY_EVENTS.init(KeyEventChannel::new())
+}
+
+impl MatrixOutput for Output<'static, AnyPin> {
+    fn select(&mut self) {
+        self.set_low();
+        cortex_m::asm::delay(SETTLE_CYCLES);
+    }
+
+    fn unselect(&mut self) {
+        self.set_high();
+    }
+}
+
+impl MatrixInput for Input<'static, AnyPin> {
+    fn is_active(&self) -> bool {
+        self.is_low()
+    }
+}
+
+#[embassy_executor::task]
+pub async fn matrix_task(mut matrix: KeyMatrix, sender: KeyEventSender) {
+    info!("Matrix scanning started: {}", matrix.direction());
+
     loop {
-        pin.wait_for_low().await;
-        let mut report = BootKeyboardReport::default();
-
-        report.keys[0] = Keyboard::A;
-        info!("Button {:?} pressed!", n);
-        gatt.hid
-            .report
-            .value_notify(con, &report.pack().unwrap())
-            .expect("Failed to send)");
-
-        pin.wait_for_high().await;
-
-        gatt.hid
-            .report
-            .value_notify(con, &BootKeyboardReport::default().pack().unwrap())
-            .expect("Failed to send)");
-        //info!("Button {:?} released!", n);
+        // Every key changes at most once per scan.
+        let mut events = [None; ROWS * COLS];
+        let mut count = 0;
+        matrix.scan(|event| {
+            events[count] = Some(event);
+            count += 1;
+        });
+
+        for event in events.into_iter().flatten() {
+            debug!("Key event: {}", event);
+            sender.send(event).await;
+        }
+
+        let interval = if HID_STATE.is_suspended() {
+            SUSPENDED_SCAN_INTERVAL
+        } else {
+            SCAN_INTERVAL
+        };
+        Timer::after(interval).await;
     }
 }
 
+#[embassy_executor::task]
+pub async fn indicator_task(mut pins: Indicators) {
+    loop {
+        let leds = HID_STATE.leds();
+        for (pin, indicator) in pins.iter_mut().zip(INDICATORS.iter()) {
+            if leds.is_on(indicator.led) == indicator.active_high {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+        HID_STATE.wait_leds().await;
+    }
+}
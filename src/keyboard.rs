@@ -0,0 +1,153 @@
+use crate::ble::{bonder::Bonder, gatt::GATTServer};
+use crate::config;
+use crate::gpio::{KeyEventReceiver, COLS, ROWS};
+use crate::hid::HID_STATE;
+use crate::keymap::action::{Command, HostAction};
+use crate::keymap::{KeySet, Keymap, Report};
+use crate::kvstore::FACTORY_RESET;
+use crate::usb::{self, TRANSPORT};
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+use futures::future::{select, Either};
+use futures::pin_mut;
+use nrf_softdevice::ble::{gatt_server::NotifyValueError, Connection};
+use nrf_softdevice::RawError;
+
+/// How often a notification is retried while the SoftDevice queue is full, and how long to
+/// wait in between. About one connection interval, after which the queue has drained.
+const NOTIFY_ATTEMPTS: usize = 4;
+const NOTIFY_RETRY: Duration = Duration::from_millis(15);
+
+/// Runs matrix events through the keymap and sends the resulting reports to the connected host.
+///
+/// Reports go to USB while it is the selected transport, otherwise to the BLE connection.
+/// When they move to the other transport, everything is released on the old one first so no
+/// key stays stuck there.
+/// Without a connection the keymap still runs, so host profile keys work while advertising,
+/// but BLE reports are dropped. Returns once a host profile key is pressed.
+///
+/// While the host is suspended, keyboard reports that only add keys are collapsed into the
+/// latest one. A report releasing a key sends the state before it first, so a key pressed and
+/// released within one batch still reaches the host. Consumer and system control reports
+/// always go out so they can wake it.
+///
+/// While a passkey is being entered for pairing, newly pressed keys go to the bonder and
+/// nothing is sent to the host. Everything is released on the host when the entry starts.
+pub async fn keyboard_task(
+    events: &KeyEventReceiver,
+    keymap: &mut Keymap<ROWS, COLS>,
+    gatt: &GATTServer,
+    bonder: &Bonder,
+    con: Option<&Connection>,
+) -> HostAction {
+    let mut last = KeySet::default();
+    let mut used_usb = TRANSPORT.uses_usb();
+    let mut entering_passkey = bonder.is_entering_passkey();
+
+    loop {
+        let receive_fut = events.receive();
+        let deadline_fut = Timer::at(keymap.next_deadline().unwrap_or(Instant::MAX));
+        let passkey_fut = bonder.wait_passkey_request();
+
+        pin_mut!(receive_fut);
+        pin_mut!(deadline_fut);
+        pin_mut!(passkey_fut);
+
+        match select(receive_fut, select(deadline_fut, passkey_fut)).await {
+            Either::Left((event, _)) => {
+                info!("Key {}:{} pressed: {}", event.row, event.col, event.pressed);
+                keymap.event(event, Instant::now());
+            }
+            Either::Right((Either::Left(_), _)) => keymap.tick(Instant::now()),
+            Either::Right((Either::Right(_), _)) => {}
+        }
+
+        let uses_usb = TRANSPORT.uses_usb();
+        if uses_usb != used_usb {
+            release_all(gatt, con, used_usb).await;
+            used_usb = uses_usb;
+        }
+
+        // Keys held while the passkey entry starts would stay stuck on the host.
+        if bonder.is_entering_passkey() && !entering_passkey {
+            release_all(gatt, con, uses_usb).await;
+        }
+        entering_passkey = bonder.is_entering_passkey();
+
+        let mut latest = None;
+        while let Some(report) = keymap.next_report() {
+            if let Report::Keyboard(keys) = report {
+                if bonder.is_entering_passkey() {
+                    keys.iter()
+                        .filter(|key| !last.contains(*key))
+                        .for_each(|key| bonder.passkey_key(key));
+                }
+                last = keys;
+            }
+            if bonder.is_entering_passkey() {
+                continue;
+            }
+
+            if uses_usb {
+                usb::send_report(&report);
+                continue;
+            }
+
+            match (con, report) {
+                (Some(con), Report::Keyboard(keys)) if HID_STATE.is_suspended() => {
+                    if let Some(held) = latest.filter(|held: &KeySet| !held.is_subset(&keys)) {
+                        notify(|| gatt.hid.send_keys(con, &held)).await;
+                    }
+                    latest = Some(keys);
+                }
+                (Some(con), report) => notify(|| gatt.hid.send_report(con, &report)).await,
+                (None, _) => {}
+            }
+        }
+
+        if let (Some(con), Some(keys)) = (con, latest) {
+            notify(|| gatt.hid.send_keys(con, &keys)).await;
+        }
+
+        while let Some(command) = keymap.next_command() {
+            match command {
+                Command::Host(action) => return action,
+                Command::Transport(transport) => TRANSPORT.set_transport(transport),
+                Command::FactoryReset => FACTORY_RESET.signal(()),
+                Command::SaveMacro(id) => {
+                    if let Some(recorded) = keymap.get_macro(id) {
+                        config::save_macro(id, recorded.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Releases every key and button on USB or the BLE connection.
+async fn release_all(gatt: &GATTServer, con: Option<&Connection>, to_usb: bool) {
+    for report in Report::released() {
+        match con {
+            _ if to_usb => usb::send_report(&report),
+            Some(con) => notify(|| gatt.hid.send_report(con, &report)).await,
+            None => {}
+        }
+    }
+}
+
+/// Sends a notification, waiting for the SoftDevice queue to drain while it is full. The
+/// report is dropped if it still doesn't fit or can't be sent at all, e.g. while the host
+/// hasn't subscribed to it.
+async fn notify(send: impl Fn() -> Result<(), NotifyValueError>) {
+    for _ in 0..NOTIFY_ATTEMPTS {
+        match send() {
+            Ok(()) => return,
+            Err(NotifyValueError::Raw(RawError::Resources)) => Timer::after(NOTIFY_RETRY).await,
+            Err(e) => {
+                warn!("Failed to send report: {}", e);
+                return;
+            }
+        }
+    }
+    warn!("Notification queue full, dropping report");
+}
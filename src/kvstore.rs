@@ -1,15 +1,16 @@
-use defmt::{info, unwrap, Format};
+use defmt::{info, unwrap, warn};
 use ekv::config::PAGE_SIZE;
-use ekv::WriteTransaction;
-use ekv::{CommitError, Database, ReadError, WriteError};
-use embassy_nrf::{
-    peripherals::QSPI,
-    qspi::{Error as FlashError, Qspi},
-};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use serde::{de::DeserializeOwned, Serialize};
+use ekv::Database;
+use embassy_nrf::{peripherals::QSPI, qspi::Qspi};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::signal::Signal;
 use static_cell::StaticCell;
 
+pub use keyboard_core::kvstore::{
+    factory_reset, mount, store, DBKey, DBReadError, DBWriteError, FormatReason, FormatRecord,
+    SerdeDB,
+};
+
 //https://www.mxic.com.tw/Lists/Datasheet/Attachments/8868/MX25R6435F,%20Wide%20Range,%2064Mb,%20v1.6.pdf
 async fn init_qspi(q: &mut Qspi<'_, QSPI>) {
     let mut id = [1; 3];
@@ -93,90 +94,6 @@ impl ekv::flash::Flash for FlashCtrl {
     }
 }
 
-#[derive(Debug, Format)]
-pub enum DBReadError {
-    IO(ReadError<FlashError>),
-    Deserialize(postcard::Error),
-}
-impl From<postcard::Error> for DBReadError {
-    fn from(value: postcard::Error) -> Self {
-        DBReadError::Deserialize(value)
-    }
-}
-impl From<ReadError<FlashError>> for DBReadError {
-    fn from(value: ReadError<FlashError>) -> Self {
-        DBReadError::IO(value)
-    }
-}
-#[derive(Debug, Format)]
-pub enum DBWriteError {
-    WriteError(WriteError<FlashError>),
-    CommitError(CommitError<FlashError>),
-    SerializeError(postcard::Error),
-}
-
-impl From<WriteError<FlashError>> for DBWriteError {
-    fn from(value: WriteError<FlashError>) -> Self {
-        DBWriteError::WriteError(value)
-    }
-}
-
-impl From<CommitError<FlashError>> for DBWriteError {
-    fn from(value: CommitError<FlashError>) -> Self {
-        DBWriteError::CommitError(value)
-    }
-}
-impl From<postcard::Error> for DBWriteError {
-    fn from(value: postcard::Error) -> Self {
-        Self::SerializeError(value)
-    }
-}
-
-pub trait SerdeDB {
-    type ReadError;
-    type WriteError;
-    async fn read<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<T, Self::ReadError>;
-
-    async fn write<T: Serialize>(
-        &self,
-        key: impl AsRef<[u8]>,
-        val: &T,
-        wtx: &mut WriteTransaction<'_, FlashCtrl, NoopRawMutex>,
-    ) -> Result<(), Self::WriteError>;
-}
-
-impl SerdeDB for Database<FlashCtrl, NoopRawMutex> {
-    type ReadError = DBReadError;
-    type WriteError = DBWriteError;
-
-    async fn read<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<T, Self::ReadError> {
-        let mut rtx = self.read_transaction().await;
-        let mut buf = [0u8; ekv::config::MAX_VALUE_SIZE];
-        let r_len = rtx.read(key.as_ref(), &mut buf).await?;
-        let data = &buf[..r_len];
-
-        let data = postcard::from_bytes::<T>(data)?;
-        Ok(data)
-    }
-
-    async fn write<T: Serialize>(
-        &self,
-        key: impl AsRef<[u8]>,
-        val: &T,
-        wtx: &mut WriteTransaction<'_, FlashCtrl, NoopRawMutex>,
-    ) -> Result<(), Self::WriteError> {
-        let mut buf = [0u8; ekv::config::MAX_VALUE_SIZE];
-        let buf = postcard::to_slice(val, &mut buf)?;
-        wtx.write(key.as_ref(), buf).await?;
-
-        Ok(())
-    }
-}
-
-pub trait DBKey {
-    fn key(&self) -> &[u8];
-}
-
 pub type KVStore = Database<FlashCtrl, NoopRawMutex>;
 
 static KVSTORE: StaticCell<KVStore> = StaticCell::new();
@@ -188,10 +105,8 @@ pub async fn init_kvstore(mut q: Qspi<'static, QSPI>) -> &'static KVStore {
     let config = ekv::Config::default();
 
     let db: Database<FlashCtrl, NoopRawMutex> = ekv::Database::new(flash, config);
-    db.format().await.expect("formatting failed");
-    if db.mount().await.is_err() {
-        info!("Formatting DB");
-        db.format().await.expect("Failed for format DB");
+    if let Err(e) = mount(&db).await {
+        panic!("Failed to mount DB: {}", e);
     }
 
     let db = KVSTORE.init(db);
@@ -199,3 +114,16 @@ pub async fn init_kvstore(mut q: Qspi<'static, QSPI>) -> &'static KVStore {
 
     db
 }
+
+/// Set to erase all stored data, see `factory_reset_task`.
+pub static FACTORY_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Formats the store once `FACTORY_RESET` is signaled and restarts, so nothing keeps using
+/// state that was loaded from it.
+#[embassy_executor::task]
+pub async fn factory_reset_task(db: &'static KVStore) {
+    FACTORY_RESET.wait().await;
+    warn!("Factory reset");
+    factory_reset(db).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
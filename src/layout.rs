@@ -0,0 +1,189 @@
+use crate::gpio::{COLS, ROWS};
+use crate::keymap::{
+    action::{Action, HostAction, Transport},
+    combo::{Combo, ComboConfig, ComboRelease},
+    leader::{LeaderConfig, LeaderSequence},
+    macros::{Macro, MacroConfig, MacroStep},
+    mouse::{Direction, MouseAction, MouseButton, MouseConfig},
+    tap_dance::{TapDance, TapDanceConfig},
+    tap_hold::{Hold, TapHold, TapHoldConfig},
+    Layer,
+};
+use alloc::{vec, vec::Vec};
+use embassy_time::Duration;
+use usbd_human_interface_device::page::{Consumer, Desktop, Keyboard::*};
+
+const ___: Action = Action::Transparent;
+const XXX: Action = Action::NoOp;
+
+macro_rules! k {
+    ($key:ident) => {
+        Action::Key($key)
+    };
+}
+
+macro_rules! host {
+    ($action:ident $(($slot:expr))?) => {
+        Action::Host(HostAction::$action$(($slot))?)
+    };
+}
+
+macro_rules! media {
+    ($usage:ident) => {
+        Action::Consumer(Consumer::$usage)
+    };
+}
+
+macro_rules! sys {
+    ($usage:ident) => {
+        Action::System(Desktop::$usage)
+    };
+}
+
+macro_rules! ms {
+    (Button($button:ident)) => {
+        Action::Mouse(MouseAction::Button(MouseButton::$button))
+    };
+    ($kind:ident($direction:ident)) => {
+        Action::Mouse(MouseAction::$kind(Direction::$direction))
+    };
+}
+
+macro_rules! out {
+    ($transport:ident) => {
+        Action::Transport(Transport::$transport)
+    };
+}
+
+/// Home-row mod: `$tap` when tapped, `$hold` modifier when held.
+macro_rules! hm {
+    ($tap:ident, $hold:ident) => {
+        Action::TapHold(TapHold {
+            tap: $tap,
+            hold: Hold::Modifier($hold),
+        })
+    };
+}
+
+pub const TAP_HOLD: TapHoldConfig = TapHoldConfig {
+    tapping_term: Duration::from_millis(200),
+    permissive_hold: true,
+    hold_on_other_key_press: false,
+    retro_tapping: false,
+};
+
+pub const TAP_DANCE: TapDanceConfig = TapDanceConfig {
+    term: Duration::from_millis(200),
+};
+
+/// Escape, Caps Lock when double tapped, LOWER while held.
+const ESC_CAPS: Action = Action::TapDance(TapDance {
+    taps: &[k!(Escape), k!(CapsLock)],
+    holds: &[Action::MomentaryLayer(LOWER)],
+});
+
+/// Override single fields with `..MouseConfig::DEFAULT` to change how the pointer feels.
+pub const MOUSE: MouseConfig = MouseConfig::DEFAULT;
+
+pub const COMBO: ComboConfig = ComboConfig {
+    window: Duration::from_millis(50),
+    release: ComboRelease::AnyKey,
+};
+
+pub const LEADER: LeaderConfig = LeaderConfig {
+    timeout: Duration::from_millis(1000),
+    max_len: 4,
+    replay_unmatched: true,
+};
+
+/// Leader sequences used while none are stored in the KV store.
+pub fn leader_sequences() -> Vec<LeaderSequence> {
+    vec![
+        // Leader C: copy, Leader V: paste, Leader X: cut
+        LeaderSequence::chord(&[C], &[LeftControl, C]),
+        LeaderSequence::chord(&[V], &[LeftControl, V]),
+        LeaderSequence::chord(&[X], &[LeftControl, X]),
+        // Leader S S: lock the screen
+        LeaderSequence::chord(&[S, S], &[LeftGUI, L]),
+        LeaderSequence::text(&[M, A], "nrf-keyboard@example.com"),
+        // Leader M M: macro 1
+        LeaderSequence::play(&[M, M], 1),
+    ]
+}
+
+pub const MACRO: MacroConfig = MacroConfig {
+    interval: Duration::from_millis(15),
+};
+
+/// Macros by ID. IDs in `RECORDED_MACROS` are replaced by recorded macros once stored.
+pub fn macros() -> Vec<Macro> {
+    vec![
+        // Select all and copy
+        Macro::new(vec![
+            MacroStep::press(LeftControl),
+            MacroStep::tap(A),
+            MacroStep::tap(C),
+            MacroStep::release(LeftControl),
+        ]),
+        // Open a terminal and print the firmware version
+        Macro::new(vec![
+            MacroStep::press(LeftControl),
+            MacroStep::press(LeftAlt),
+            MacroStep::tap(T),
+            MacroStep::release(LeftAlt),
+            MacroStep::release(LeftControl),
+            MacroStep::Delay(500),
+            MacroStep::text(concat!(
+                "echo nrf-keyboard ",
+                env!("CARGO_PKG_VERSION"),
+                "\n"
+            )),
+        ]),
+    ]
+}
+
+pub const BASE: u8 = 0;
+pub const LOWER: u8 = 1;
+pub const RAISE: u8 = 2;
+
+#[rustfmt::skip]
+pub static LAYERS: [Layer<ROWS, COLS>; 3] = [
+    // BASE
+    [
+        [k!(Tab),         k!(Q),       k!(W),       k!(E),       k!(R),                       k!(T),     k!(Y),     k!(U),                       k!(I),         k!(O),      k!(P),            k!(DeleteBackspace)],
+        [ESC_CAPS,        hm!(A, LeftGUI), hm!(S, LeftAlt), hm!(D, LeftControl), hm!(F, LeftShift), k!(G), k!(H), hm!(J, RightShift), hm!(K, RightControl), hm!(L, RightAlt),      k!(Semicolon),    k!(Apostrophe)],
+        [k!(LeftShift),   k!(Z),       k!(X),       k!(C),       k!(V),                       k!(B),     k!(N),     k!(M),                       k!(Comma),     k!(Dot),    k!(ForwardSlash), k!(ReturnEnter)],
+        [k!(LeftControl), k!(LeftGUI), k!(LeftAlt), Action::Leader, Action::MomentaryLayer(LOWER), k!(Space), k!(Space), Action::MomentaryLayer(RAISE), k!(LeftArrow), k!(DownArrow), k!(UpArrow), k!(RightArrow)],
+    ],
+    // LOWER
+    [
+        [k!(Grave),       k!(Keyboard1), k!(Keyboard2), k!(Keyboard3), k!(Keyboard4), k!(Keyboard5), k!(Keyboard6), k!(Keyboard7), k!(Keyboard8), k!(Keyboard9), k!(Keyboard0), ___],
+        [___,             k!(F1),        k!(F2),        k!(F3),        k!(F4),        k!(F5),        k!(F6),        k!(Minus),     k!(Equal),     k!(LeftBrace), k!(RightBrace), k!(Backslash)],
+        [___,             k!(F7),        k!(F8),        k!(F9),        k!(F10),       k!(F11),       k!(F12),       host!(Select(0)), host!(Select(1)), host!(Select(2)), host!(Clear), ___],
+        [___,             media!(Mute),  media!(VolumeDecrement), media!(VolumeIncrement), ___, media!(PlayPause), media!(ScanNextTrack), Action::ToggleLayer(RAISE), k!(Home), k!(PageDown), k!(PageUp), k!(End)],
+    ],
+    // RAISE
+    [
+        [___,             out!(Auto),  out!(Usb),   out!(Ble),   ms!(Wheel(Up)),              ms!(Move(Up)), ms!(Wheel(Down)), k!(Keyboard7),               k!(Keyboard8), k!(Keyboard9), Action::Macro(0), ___],
+        [___,             media!(DisplayBrightnessDecrement), media!(DisplayBrightnessIncrement), ms!(Wheel(Left)), ms!(Move(Left)), ms!(Move(Down)), ms!(Move(Right)), k!(Keyboard4),               k!(Keyboard5), k!(Keyboard6), Action::Macro(1), ___],
+        [___,             sys!(SystemSleep), sys!(SystemWakeUp), ms!(Wheel(Right)), ms!(Button(Left)), ms!(Button(Middle)), ms!(Button(Right)), k!(Keyboard1),               k!(Keyboard2), k!(Keyboard3), host!(Pair), ___],
+        [___,             Action::RecordMacro(8), Action::StopRecording, Action::Macro(8), Action::OneShotLayer(LOWER), ___,       ___,       ___,                         k!(Keyboard0), XXX,           Action::FactoryReset, ___],
+    ],
+];
+
+pub static COMBOS: [Combo; 2] = [
+    // W + E
+    Combo {
+        keys: &[(0, 2), (0, 3)],
+        action: k!(Escape),
+        timeout: None,
+        layers: &[BASE],
+    },
+    // U + I
+    Combo {
+        keys: &[(0, 7), (0, 8)],
+        action: k!(DeleteBackspace),
+        timeout: None,
+        layers: &[BASE],
+    },
+];
@@ -4,37 +4,44 @@
 #![feature(generic_const_exprs)]
 #![feature(error_in_core)]
 
+pub mod battery;
 pub mod ble;
-pub mod debouncer;
+pub mod config;
 pub mod gpio;
+pub mod keyboard;
 pub mod kvstore;
+pub mod layout;
+pub mod usb;
+pub use keyboard_core::{hid, keymap, matrix, passkey};
 extern crate alloc;
-use alloc::string::String;
-use ble::{
-    bonder::Bonder,
-    gatt::GATTServer,
-    softdevice::{self, AdvData},
-};
+use battery::{battery_task, notify_battery_level, BatterySource, BATTERY};
+use ble::{advertising::AdvPayload, bonder::Bonder, gatt::GATTServer, softdevice};
+use config::{macro_store_task, DeviceConfig};
 use defmt::info;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
 use embassy_nrf::{
     self as _, bind_interrupts,
-    gpio::{AnyPin, Input, Pin},
+    gpio::{Input, Level, Output, OutputDrive, Pin, Pull},
     interrupt::{Interrupt, InterruptExt, Priority},
     peripherals::{self},
     qspi::{self, Frequency, Qspi},
+    saadc::{self, ChannelConfig, Saadc, VddInput, VddhDiv5Input},
+    usb::{vbus_detect::SoftwareVbusDetect, Driver},
 };
 use embassy_time::Timer;
 use embedded_alloc::Heap;
 use futures::future::{select, Either};
 use futures::pin_mut;
-use gpio::button_task;
-use kvstore::{init_kvstore, KVStore};
+use gpio::{indicator_task, init_key_events, matrix_task, Indicators, KeyEventReceiver, KeyMatrix};
+use keyboard::keyboard_task;
+use keymap::Keymap;
+use kvstore::{factory_reset_task, init_kvstore, KVStore};
+use matrix::{DiodeDirection, Matrix};
 use nrf_softdevice::{self as _, ble::gatt_server, gatt_server, Softdevice};
 use panic_probe as _;
-use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
+use usb::{init_vbus, usb_task, UsbDriver};
 
 use crate::ble::softdevice::sync_peers;
 
@@ -45,22 +52,34 @@ bind_interrupts!(struct QSPIIRQ {
     QSPI => qspi::InterruptHandler<peripherals::QSPI>;
 });
 
+bind_interrupts!(struct SAADCIRQ {
+    SAADC => saadc::InterruptHandler;
+});
+
+bind_interrupts!(struct USBIRQ {
+    USBD => embassy_nrf::usb::InterruptHandler<peripherals::USBD>;
+});
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     init_heap();
-    let (qspi, btn) = init_peripherials();
+    let (qspi, matrix, indicators, saadc, usb_driver, vbus) = init_peripherials();
+    let key_events = init_key_events();
+    spawner.must_spawn(matrix_task(matrix, key_events.sender()));
+    spawner.must_spawn(indicator_task(indicators));
+
     let db = init_kvstore(qspi).await;
+    spawner.must_spawn(factory_reset_task(db));
+    spawner.must_spawn(macro_store_task(db));
 
-    let (sd, gatt, bonder, adv) = softdevice::init(spawner, db).await;
+    let device = DeviceConfig::load(db).await;
 
-    init_bt(spawner, sd, &gatt, bonder, adv, btn, db).await;
-}
-#[derive(Debug, Deserialize, Serialize)]
-struct DeviceConfig {
-    advertising_name: String,
-    appearance: u8,
-}
+    let (sd, gatt, bonder, adv) = softdevice::init(spawner, db, vbus, &device).await;
+    spawner.must_spawn(battery_task(sd, saadc, gatt.bas));
+    spawner.must_spawn(usb_task(usb_driver));
 
+    init_bt(spawner, sd, &gatt, bonder, adv, key_events.receiver(), db).await;
+}
 fn init_heap() {
     use core::mem::MaybeUninit;
     const HEAP_SIZE: usize = 1024 * 30;
@@ -71,7 +90,11 @@ fn init_heap() {
 
 fn init_peripherials<'a>() -> (
     Qspi<'a, embassy_nrf::peripherals::QSPI>,
-    Input<'static, AnyPin>,
+    KeyMatrix,
+    Indicators,
+    Saadc<'static, 1>,
+    UsbDriver,
+    &'static SoftwareVbusDetect,
 ) {
     Interrupt::RNG.set_priority(Priority::P3);
     let mut config = embassy_nrf::config::Config::default();
@@ -98,9 +121,51 @@ fn init_peripherials<'a>() -> (
         p.QSPI, QSPIIRQ, p.P1_03, p.P1_06, p.P1_05, p.P1_04, p.P1_02, p.P1_01, config,
     );
 
-    let btn = Input::new(p.P1_00.degrade(), embassy_nrf::gpio::Pull::Up);
-
-    (qspi, btn)
+    let rows = [
+        p.P0_02.degrade(),
+        p.P0_03.degrade(),
+        p.P0_04.degrade(),
+        p.P0_05.degrade(),
+    ]
+    .map(|pin| Input::new(pin, Pull::Up));
+
+    let cols = [
+        p.P0_06.degrade(),
+        p.P0_07.degrade(),
+        p.P0_08.degrade(),
+        p.P0_11.degrade(),
+        p.P0_12.degrade(),
+        p.P0_13.degrade(),
+        p.P0_14.degrade(),
+        p.P0_15.degrade(),
+        p.P0_16.degrade(),
+        p.P0_17.degrade(),
+        p.P0_19.degrade(),
+        p.P0_20.degrade(),
+    ]
+    .map(|pin| Output::new(pin, Level::High, OutputDrive::Standard));
+
+    let matrix = Matrix::new(rows, cols, DiodeDirection::Row2Col);
+
+    // Driven to the host LED state by `indicator_task`.
+    let indicators =
+        [p.P0_24.degrade()].map(|pin| Output::new(pin, Level::Low, OutputDrive::Standard));
+
+    let channel = match BATTERY.source {
+        BatterySource::Vddh => ChannelConfig::single_ended(VddhDiv5Input),
+        BatterySource::Vdd => ChannelConfig::single_ended(VddInput),
+    };
+    let mut config = saadc::Config::default();
+    config.oversample = saadc::Oversample::OVER8X;
+
+    Interrupt::SAADC.set_priority(Priority::P3);
+    let saadc = Saadc::new(p.SAADC, SAADCIRQ, config, [channel]);
+
+    Interrupt::USBD.set_priority(Priority::P2);
+    let vbus = init_vbus();
+    let usb_driver = Driver::new(p.USBD, USBIRQ, vbus);
+
+    (qspi, matrix, indicators, saadc, usb_driver, vbus)
 }
 static BONDER: StaticCell<Bonder> = StaticCell::new();
 async fn init_bt(
@@ -108,31 +173,56 @@ async fn init_bt(
     sd: &'static Softdevice,
     server: &GATTServer,
     bonder: Bonder,
-    adv: AdvData,
-    mut btn: Input<'static, AnyPin>,
+    adv: AdvPayload,
+    key_events: KeyEventReceiver,
     db: &'static KVStore,
 ) {
     info!("Softdevice initialized");
     info!("Server: {}", server);
     let bonder = BONDER.init(bonder);
+    let mut keymap = Keymap::with_tap_hold(&layout::LAYERS, layout::TAP_HOLD)
+        .with_mouse(layout::MOUSE)
+        .with_tap_dance(layout::TAP_DANCE)
+        .with_combos(&layout::COMBOS, layout::COMBO)
+        .with_leader(layout::LEADER, config::load_leader_sequences(db).await)
+        .with_macros(layout::MACRO, config::load_macros(db).await);
 
     loop {
-        let con = softdevice::advertise(sd, &adv, bonder)
-            .await
-            .expect("failed to advertise");
+        let con = {
+            // Keep handling keys while advertising, so the host can be switched before connecting.
+            let adv_fut = softdevice::advertise(sd, &adv, bonder);
+            let idle_fut = keyboard_task(&key_events, &mut keymap, server, bonder, None);
+
+            pin_mut!(adv_fut);
+            pin_mut!(idle_fut);
+
+            match select(adv_fut, idle_fut).await {
+                Either::Left((con, _)) => con.expect("failed to advertise"),
+                Either::Right((action, _)) => {
+                    softdevice::apply_host_action(bonder, action, None);
+                    continue;
+                }
+            }
+        };
 
         info!("Advertising Completed");
+        server.hid.reset(sd).expect("Failed to reset HID service");
         info!("Spawning GATT Server");
 
         let gatt_fut = gatt_server::run(&con, server, |f| {});
-        let btn_fut = button_task(1, &mut btn, server, &con);
+        let keyboard_fut = keyboard_task(&key_events, &mut keymap, server, bonder, Some(&con));
+        let battery_fut = notify_battery_level(&server.bas, &con);
 
         pin_mut!(gatt_fut);
-        pin_mut!(btn_fut);
-
-        select(gatt_fut, btn_fut).await;
-        //con.disconnect().expect("Failed to disconnect");
+        pin_mut!(keyboard_fut);
+        pin_mut!(battery_fut);
+
+        if let Either::Right((Either::Left((action, _)), _)) =
+            select(gatt_fut, select(keyboard_fut, battery_fut)).await
+        {
+            softdevice::apply_host_action(bonder, action, Some(&con));
+        }
+        bonder.cancel_passkey();
         info!("Gatt Server exited")
     }
 }
-
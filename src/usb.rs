@@ -0,0 +1,283 @@
+use crate::config::DEVICE_INFO;
+use crate::hid::{Leds, HID_STATE, MAX_REPORT_LEN, REPORT_MAP};
+use crate::keymap::action::Transport;
+use crate::keymap::{LatestReports, Report};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use defmt::{info, warn};
+use embassy_nrf::peripherals::USBD;
+use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
+use embassy_nrf::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::hid::{self, HidWriter, OutResponse, ReportId, RequestHandler, State};
+use embassy_usb::{Builder, Handler};
+use futures::future::{join, select, Either};
+use futures::pin_mut;
+use nrf_softdevice::{raw, SocEvent};
+use static_cell::StaticCell;
+
+pub type UsbDriver = Driver<'static, USBD, &'static SoftwareVbusDetect>;
+
+static VBUS: StaticCell<SoftwareVbusDetect> = StaticCell::new();
+
+/// VBUS detection fed by the SoftDevice, which owns the POWER peripheral.
+pub fn init_vbus() -> &'static SoftwareVbusDetect {
+    VBUS.init(SoftwareVbusDetect::new(false, false))
+}
+
+/// Enables the SoftDevice USB power events and picks up a cable that was already plugged in
+/// at boot. Must be called once the SoftDevice is enabled.
+pub fn enable_power_events(vbus: &SoftwareVbusDetect) {
+    let mut status = 0;
+    unsafe {
+        raw::sd_power_usbdetected_enable(1);
+        raw::sd_power_usbpwrrdy_enable(1);
+        raw::sd_power_usbremoved_enable(1);
+        raw::sd_power_usbregstatus_get(&mut status);
+    }
+
+    if status & raw::NRF_POWER_USBREGSTATUS_VBUSDETECT_MSK != 0 {
+        vbus.detected(true);
+    }
+    if status & raw::NRF_POWER_USBREGSTATUS_OUTPUTRDY_MSK != 0 {
+        vbus.ready();
+    }
+}
+
+pub fn on_soc_event(vbus: &SoftwareVbusDetect, event: SocEvent) {
+    match event {
+        SocEvent::PowerUsbDetected => vbus.detected(true),
+        SocEvent::PowerUsbRemoved => vbus.detected(false),
+        SocEvent::PowerUsbPowerReady => vbus.ready(),
+        _ => {}
+    }
+}
+
+/// Selected transport and USB bus state, deciding where keyboard reports go.
+pub struct TransportState {
+    transport: AtomicU8,
+    usb_configured: AtomicBool,
+    usb_suspended: AtomicBool,
+}
+
+pub static TRANSPORT: TransportState = TransportState::new();
+
+impl TransportState {
+    pub const fn new() -> Self {
+        Self {
+            transport: AtomicU8::new(Transport::Auto as u8),
+            usb_configured: AtomicBool::new(false),
+            usb_suspended: AtomicBool::new(false),
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self.transport.load(Ordering::Relaxed) {
+            t if t == Transport::Usb as u8 => Transport::Usb,
+            t if t == Transport::Ble as u8 => Transport::Ble,
+            _ => Transport::Auto,
+        }
+    }
+
+    pub fn set_transport(&self, transport: Transport) {
+        info!("Transport: {}", transport);
+        self.transport.store(transport as u8, Ordering::Relaxed);
+    }
+
+    /// True while the USB host has configured the device and isn't suspended.
+    pub fn usb_ready(&self) -> bool {
+        self.usb_configured.load(Ordering::Relaxed) && !self.usb_suspended.load(Ordering::Relaxed)
+    }
+
+    /// True if reports should go to USB instead of BLE. Auto stays on USB while the host is
+    /// suspended, so a key press can wake it up.
+    pub fn uses_usb(&self) -> bool {
+        match self.transport() {
+            Transport::Auto => self.usb_configured(),
+            Transport::Usb => true,
+            Transport::Ble => false,
+        }
+    }
+
+    /// True while the USB host has configured the device, suspended or not.
+    pub fn usb_configured(&self) -> bool {
+        self.usb_configured.load(Ordering::Relaxed)
+    }
+
+    fn set_usb_configured(&self, configured: bool) {
+        self.usb_configured.store(configured, Ordering::Relaxed);
+    }
+
+    fn set_usb_suspended(&self, suspended: bool) {
+        self.usb_suspended.store(suspended, Ordering::Relaxed);
+    }
+}
+
+static USB_REPORTS: Channel<CriticalSectionRawMutex, Report, 16> = Channel::new();
+static USB_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Reports collected while the host is suspended, queued once the bus resumes.
+static USB_SUSPENDED_REPORTS: Mutex<CriticalSectionRawMutex, RefCell<LatestReports>> =
+    Mutex::new(RefCell::new(LatestReports::new()));
+
+/// Queues a report for the USB interface. Reports are dropped while USB isn't configured, so
+/// a forced USB transport doesn't replay stale keys once it is plugged in. While the host is
+/// suspended it is woken up and only the latest report of each kind is kept, those go out once
+/// the bus resumes.
+pub fn send_report(report: &Report) {
+    if !TRANSPORT.usb_configured() {
+        return;
+    }
+    if !TRANSPORT.usb_ready() {
+        USB_SUSPENDED_REPORTS.lock(|latest| latest.borrow_mut().push(*report));
+        USB_WAKEUP.signal(());
+        return;
+    }
+    queue_report(report);
+}
+
+fn queue_report(report: &Report) {
+    if USB_REPORTS.try_send(*report).is_err() {
+        warn!("USB report queue full, dropping report");
+    }
+}
+
+/// Queues the reports collected while suspended, or drops them if the host is gone.
+fn flush_suspended_reports(send: bool) {
+    let reports = USB_SUSPENDED_REPORTS.lock(|latest| latest.borrow_mut().take());
+    if send {
+        reports.for_each(|report| queue_report(&report));
+    }
+}
+
+struct UsbHandler;
+
+impl Handler for UsbHandler {
+    fn enabled(&mut self, enabled: bool) {
+        info!("USB enabled: {}", enabled);
+        if !enabled {
+            TRANSPORT.set_usb_configured(false);
+            TRANSPORT.set_usb_suspended(false);
+            flush_suspended_reports(false);
+        }
+    }
+
+    fn reset(&mut self) {
+        TRANSPORT.set_usb_configured(false);
+        flush_suspended_reports(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        info!("USB configured: {}", configured);
+        TRANSPORT.set_usb_configured(configured);
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        info!("USB suspended: {}", suspended);
+        TRANSPORT.set_usb_suspended(suspended);
+        if !suspended {
+            flush_suspended_reports(TRANSPORT.usb_configured());
+        }
+    }
+}
+
+/// Picks up the LED output report the host sends through SET_REPORT.
+struct LedsHandler;
+
+impl RequestHandler for LedsHandler {
+    fn set_report(&self, id: ReportId, data: &[u8]) -> OutResponse {
+        // Depending on the host the report ID is repeated as the first byte.
+        let leds = match (id, data) {
+            (ReportId::Out(id), [first, leds]) if *first == id => *leds,
+            (ReportId::Out(_), [leds]) => *leds,
+            _ => return OutResponse::Rejected,
+        };
+        info!("Host LEDs: {=u8:#04x}", leds);
+        HID_STATE.set_leds(Leds(leds));
+        OutResponse::Accepted
+    }
+}
+
+#[embassy_executor::task]
+pub async fn usb_task(driver: UsbDriver) {
+    let mut config = embassy_usb::Config::new(DEVICE_INFO.vendor_id, DEVICE_INFO.product_id);
+    config.manufacturer = Some(DEVICE_INFO.manufacturer);
+    config.product = Some(DEVICE_INFO.model);
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+    config.supports_remote_wakeup = true;
+
+    let mut device_descriptor = [0; 256];
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut handler = UsbHandler;
+    let leds_handler = LedsHandler;
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut device_descriptor,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    builder.handler(&mut handler);
+
+    let hid_config = hid::Config {
+        report_descriptor: REPORT_MAP.bytes(),
+        request_handler: Some(&leds_handler),
+        poll_ms: 1,
+        max_packet_size: 64,
+    };
+    let mut writer = HidWriter::<_, 64>::new(&mut builder, &mut state, hid_config);
+    let mut usb = builder.build();
+
+    // Runs the bus until the host suspends it, then waits for it to resume or for a report
+    // to wake it up. The host may not have enabled remote wakeup, the report then waits.
+    let usb_fut = async {
+        loop {
+            USB_WAKEUP.reset();
+            usb.run_until_suspend().await;
+
+            let wakeup = {
+                let resume_fut = usb.wait_resume();
+                let wakeup_fut = USB_WAKEUP.wait();
+                pin_mut!(resume_fut);
+                pin_mut!(wakeup_fut);
+                matches!(select(resume_fut, wakeup_fut).await, Either::Right(_))
+            };
+            if wakeup {
+                if let Err(e) = usb.remote_wakeup().await {
+                    warn!("USB remote wakeup failed: {}", e);
+                }
+            }
+        }
+    };
+
+    let write_fut = async {
+        loop {
+            let report = USB_REPORTS.receive().await;
+            let mut buf = [0u8; MAX_REPORT_LEN];
+            let Some((id, len)) = REPORT_MAP.encode(&report, &mut buf) else {
+                continue;
+            };
+
+            // Reports on an interface with report IDs are prefixed by the ID.
+            let mut packet = [0u8; 1 + MAX_REPORT_LEN];
+            packet[0] = id;
+            packet[1..=len].copy_from_slice(&buf[..len]);
+
+            if let Err(e) = writer.write(&packet[..=len]).await {
+                warn!("Failed to send USB report: {}", e);
+            }
+        }
+    };
+
+    join(usb_fut, write_fut).await;
+}